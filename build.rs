@@ -2,28 +2,46 @@ use std::env;
 use std::fs;
 use std::path::Path;
 
-const MODEL_URL: &str = "https://raw.githubusercontent.com/GregorR/rnnoise-models/master/somnolent-hogwash-2018-09-01/sh.rnnn";
-const MODEL_FILENAME: &str = "rnnoise_model.rnnn";
+const MODELS_URL: &str = "https://raw.githubusercontent.com/GregorR/rnnoise-models/master";
 
-fn main() {
-    let out_dir = env::var("OUT_DIR").unwrap();
-    let model_path = Path::new(&out_dir).join(MODEL_FILENAME);
+/// RNNoise models to bundle, as (name, path within the models repo)
+const MODELS: [(&str, &str); 5] = [
+    ("somnolent-hogwash", "somnolent-hogwash-2018-09-01/sh.rnnn"),
+    ("beguiling-drafter", "beguiling-drafter-2018-08-30/bd.rnnn"),
+    ("conjoined-burgers", "conjoined-burgers-2018-08-28/cb.rnnn"),
+    ("leavened-quisling", "leavened-quisling-2018-08-31/lq.rnnn"),
+    ("marathon-prescription", "marathon-prescription-2018-08-29/mp.rnnn"),
+];
+
+/// hls.js, which the page uses to play HLS in browsers that can't natively
+const HLS_JS_URL: &str = "https://cdn.jsdelivr.net/npm/hls.js@1.5.17/dist/hls.min.js";
+
+/// Downloads `url` to `path`, unless it's already there
+fn download(url: &str, path: &Path, what: &str) {
+    if path.exists() {
+        return;
+    }
+    println!("cargo:warning=Downloading {}...", what);
 
-    // Only download if not already present
-    if !model_path.exists() {
-        println!("cargo:warning=Downloading RNNoise model...");
+    let response = ureq::get(url).call().unwrap_or_else(|err| panic!("Failed to download {}: {}", what, err));
 
-        let response = ureq::get(MODEL_URL)
-            .call()
-            .expect("Failed to download RNNoise model");
+    let mut file = fs::File::create(path).unwrap_or_else(|err| panic!("Failed to create file for {}: {}", what, err));
+    let mut body = response.into_body();
+    std::io::copy(&mut body.as_reader(), &mut file).unwrap_or_else(|err| panic!("Failed to write {}: {}", what, err));
 
-        let mut file = fs::File::create(&model_path).expect("Failed to create model file");
-        let mut body = response.into_body();
-        std::io::copy(&mut body.as_reader(), &mut file).expect("Failed to write model file");
+    println!("cargo:warning={} downloaded successfully", what);
+}
 
-        println!("cargo:warning=RNNoise model downloaded successfully");
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+
+    for (name, model) in MODELS {
+        let model_path = Path::new(&out_dir).join(format!("{}.rnnn", name));
+        download(&format!("{}/{}", MODELS_URL, model), &model_path, &format!("RNNoise model {}", name));
     }
 
+    download(HLS_JS_URL, &Path::new(&out_dir).join("hls.min.js"), "hls.js");
+
     // Tell Cargo to rerun if model is missing
     println!("cargo:rerun-if-changed=build.rs");
 }
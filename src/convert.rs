@@ -1,6 +1,15 @@
 use std::io::Error;
-use serde::Deserialize;
-use tokio::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{ChildStdout, Command};
+use tokio_util::sync::CancellationToken;
+
+use crate::probe::{probe_file, FfFormat};
+use crate::rnnoise::Model;
 
 #[cfg(unix)]
 use std::os::unix::process::ExitStatusExt;
@@ -21,143 +30,880 @@ pub enum ConvertError {
 
     #[error("Interrupted by signal")]
     Interrupted,
+
+    #[error("No image-based subtitle stream {0} to burn in")]
+    NoBitmapSubtitles(usize),
+}
+
+/// Format of the converted output
+#[derive(clap::ValueEnum, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// A single MP4, capped at 1080p
+    Mp4,
+    /// HLS with a rendition per rung of the bitrate ladder
+    Hls,
 }
 
-pub async fn convert_to_mp4(input_path: &str, output_path: &str, codec: Option<&str>, force_reencode: bool, denoise: bool) -> Result<(), ConvertError> {
-    let tmp_output_path = output_path.to_string() + ".tmp";
+impl OutputFormat {
+    /// The format of an existing output, which may have been converted with different settings
+    pub fn of_output(path: &Path) -> Self {
+        if path.extension().is_some_and(|ext| ext == "m3u8") {
+            OutputFormat::Hls
+        } else {
+            OutputFormat::Mp4
+        }
+    }
+}
+
+/// Renditions produced for HLS output, as (height, video bitrate)
+const HLS_LADDER: [(u32, &str); 3] = [(1080, "5000k"), (720, "2800k"), (480, "1400k")];
+
+/// Target length of each HLS segment, in seconds
+const HLS_SEGMENT_SECONDS: u32 = 6;
+
+/// Name of the HLS master playlist within the output directory
+pub const HLS_MASTER_PLAYLIST: &str = "master.m3u8";
+
+/// What a running conversion is doing
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Reading through an audio track to measure its loudness, before converting
+    MeasuringLoudness,
+    #[default]
+    Converting,
+}
+
+/// Progress of a running conversion, as reported by ffmpeg.
+/// Each pass that measures loudness reports its own progress from the start, before the conversion's.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Progress {
+    pub stage: Stage,
+    /// Seconds of output written so far
+    pub out_time: f64,
+    /// Duration of the input in seconds, if ffprobe knows it
+    pub duration: Option<f64>,
+    /// Encoding speed, as a multiple of realtime
+    pub speed: Option<f64>,
+    /// Fraction of the input converted, from 0 to 1
+    pub fraction: Option<f64>,
+    /// Estimated seconds until the conversion finishes
+    pub eta: Option<f64>,
+}
 
-    if std::path::Path::new(tmp_output_path.as_str()).exists() {
+impl Progress {
+    fn update_estimates(&mut self) {
+        self.fraction = self
+            .duration
+            .filter(|duration| *duration > 0.0)
+            .map(|duration| (self.out_time / duration).clamp(0.0, 1.0));
+        self.eta = match (self.duration, self.speed) {
+            (Some(duration), Some(speed)) if speed > 0.0 => Some((duration - self.out_time).max(0.0) / speed),
+            _ => None,
+        };
+    }
+}
+
+/// Called whenever ffmpeg reports progress
+pub type ProgressFn<'a> = dyn Fn(Progress) + Send + Sync + 'a;
+
+/// Path that a conversion writes to until it has finished
+pub fn in_progress_path(output_path: &Path) -> PathBuf {
+    let mut path = output_path.as_os_str().to_owned();
+    path.push(".tmp");
+    path.into()
+}
+
+/// Settings for `convert_to_mp4`
+#[derive(Debug, Clone, Copy)]
+pub struct Mp4Options<'a> {
+    /// Video encoder, when the video is re-encoded; libx264 if not set
+    pub codec: Option<&'a str>,
+    /// Re-encode the video even if it could be copied
+    pub force_reencode: bool,
+    pub audio_filters: &'a AudioFilters,
+    /// Fragment the output, so it can be played while it's still being written
+    pub progressive: bool,
+    /// Subtitle stream (counting from 0) to draw onto the video, which means re-encoding it
+    pub burn_subtitles: Option<usize>,
+    /// Stops the conversion, leaving no output behind
+    pub cancel: &'a CancellationToken,
+}
+
+/// Filter that draws an image-based subtitle stream (counting from 0) over the first video stream.
+/// Text subtitles are served as WebVTT instead, so they can't be burned in.
+fn burn_in_filter(streams: &FfFormat, subtitle_stream: usize) -> Result<String, ConvertError> {
+    match streams.subtitle_streams().nth(subtitle_stream) {
+        Some(stream) if !stream.is_text_subtitle() => Ok(format!("[0:v:0][0:s:{}]overlay", subtitle_stream)),
+        _ => Err(ConvertError::NoBitmapSubtitles(subtitle_stream)),
+    }
+}
+
+/// Converts `input_path` to an MP4 at `output_path`, returning what ffprobe found out about the input
+pub async fn convert_to_mp4(input_path: &str, output_path: &str, options: Mp4Options<'_>, on_progress: &ProgressFn<'_>) -> Result<FfFormat, ConvertError> {
+    let Mp4Options { codec, force_reencode, audio_filters, progressive, burn_subtitles, cancel } = options;
+    let tmp_output_path = in_progress_path(Path::new(output_path));
+    let tmp_output_path = tmp_output_path.to_str().unwrap();
+
+    if Path::new(tmp_output_path).exists() {
         return Err(ConvertError::InProgress);
     }
 
     let codec = codec.unwrap_or("libx264");
     const MAX_W: u32 = 1920;
     const MAX_H: u32 = 1080;
-    let vf = format!("scale=ceil(iw*min(1\\,min({}/iw\\,{}/ih))/2)*2:-2", MAX_W, MAX_H);
-
-    // Build audio filter chain
-    let model_path = crate::rnnoise::get_model_path();
-    let model_path_str = model_path.to_str().unwrap();
-    let af = if denoise {
-        format!("arnndn=m={}:mix=0.5", model_path_str)
-    } else {
-        "anull".to_string()
-    };
+    let mut vf = format!("scale=ceil(iw*min(1\\,min({}/iw\\,{}/ih))/2)*2:-2", MAX_W, MAX_H);
 
     let streams = probe_file(input_path).await?;
 
+    // Burned-in subtitles go through the same filter as the scaling, which is then mapped in place of the video
+    let video_map = match burn_subtitles {
+        Some(subtitle_stream) => {
+            vf = format!("{},{}[v]", burn_in_filter(&streams, subtitle_stream)?, vf);
+            "[v]"
+        }
+        None => "0:v:0",
+    };
+
     if let Some(video) = streams.video() {
 
+        let movflags = if progressive {
+            "frag_keyframe+empty_moov+default_base_moof"
+        } else {
+            "faststart"
+        };
+
+        // Every audio track is kept, along with its language and title
         #[rustfmt::skip]
         let mut args = vec![
             "-i", input_path,
-            "-movflags", "faststart",
-            "-af", &af,
-            "-c:a", "aac",
+            "-map", video_map,
+            "-map", "0:a?",
+            "-movflags", movflags,
             "-f", "mp4",
         ];
-        
+
+        // Audio only needs re-encoding if it's being filtered, or MP4 can't hold it
+        let audio_args = if audio_filters.is_empty() && streams.audio_streams().all(|audio| audio.is_mp4_audio()) {
+            vec!["-c:a".to_string(), "copy".to_string()]
+        } else {
+            let mut audio_args = audio_filters.track_args(input_path, &streams, cancel, on_progress).await?;
+            audio_args.extend(["-c:a".to_string(), "aac".to_string()]);
+            audio_args
+        };
+        args.extend(audio_args.iter().map(String::as_str));
+
         let codec_name = video.codec_name.clone().unwrap_or_else(|| "".into());
-        if !force_reencode && (codec_name == "h264" || codec_name == "mpeg4" || codec_name == "hevc") {
+        if !force_reencode && burn_subtitles.is_none() && (codec_name == "h264" || codec_name == "mpeg4" || codec_name == "hevc") {
             args.extend_from_slice(&["-c:v", "copy"]);
+            // Use hvc1 tag for HEVC to ensure QuickTime compatibility
+            if is_hevc(codec) {
+                args.extend_from_slice(&["-tag:v", "hvc1"]);
+            }
         } else {
             args.extend_from_slice(&["-c:v", codec]);
-            // VideoToolbox encoders don't support -preset, use -realtime instead
-            if codec.contains("videotoolbox") {
-                args.extend_from_slice(&["-realtime", "0"]);
-            } else {
-                args.extend_from_slice(&["-preset", "ultrafast"]);
-            }
+            args.extend(encoder_args(codec));
             args.extend_from_slice(&["-filter_complex", &vf]);
         }
-        // Use hvc1 tag for HEVC to ensure QuickTime compatibility
-        if codec.contains("hevc") || codec.contains("h265") {
-            args.extend_from_slice(&["-tag:v", "hvc1"]);
-        }
-        args.push(tmp_output_path.as_str());
+        args.push(tmp_output_path);
         
-        println!("{:?}", args.join(" "));
-        
-        let mut proc = Command::new("ffmpeg")
-            .args(args)
-            .kill_on_drop(true)
-            .spawn()?;
+        run_ffmpeg(&args, Path::new(tmp_output_path), Path::new(output_path), streams.duration(), cancel, on_progress).await?;
+        Ok(streams)
+    } else {
+        Err(ConvertError::HandBrakeError("no video stream found".to_string()))
+    }
+}
 
-        let status = proc.wait().await?;
-        
-        #[cfg(unix)]
-        {
-            // Check if the process was killed by SIGINT (Ctrl+C)
-            // - signal() returns Some(2) if terminated by signal
-            // - ffmpeg exits with code 255 when it catches SIGINT internally
-            if status.signal() == Some(2) || status.code() == Some(255) {
-                // Clean up the temp file before propagating
-                let _ = std::fs::remove_file(&tmp_output_path);
-                return Err(ConvertError::Interrupted);
-            }
+/// Converts `input_path` to HLS in `output_dir`: a `master.m3u8` playlist plus a rendition for each rung
+/// of `HLS_LADDER` that's no taller than the source. Always re-encodes, since every rendition is scaled.
+/// If `burn_subtitles` is set, that subtitle stream (counting from 0) is drawn onto every rendition.
+/// Returns what ffprobe found out about the input.
+pub async fn convert_to_hls(input_path: &str, output_dir: &Path, codec: Option<&str>, audio_filters: &AudioFilters, burn_subtitles: Option<usize>, cancel: &CancellationToken, on_progress: &ProgressFn<'_>) -> Result<FfFormat, ConvertError> {
+    let tmp_output_dir = in_progress_path(output_dir);
+
+    if tmp_output_dir.exists() {
+        return Err(ConvertError::InProgress);
+    }
+
+    let codec = codec.unwrap_or("libx264");
+
+    let streams = probe_file(input_path).await?;
+
+    let Some(video) = streams.video() else {
+        return Err(ConvertError::HandBrakeError("no video stream found".to_string()));
+    };
+    let audio_tracks: Vec<_> = streams.audio_streams().collect();
+
+    // Don't upscale, but always produce at least the smallest rendition
+    let source_height = video.height.unwrap_or(u32::MAX);
+    let mut ladder: Vec<_> = HLS_LADDER.iter().filter(|(height, _)| *height <= source_height).collect();
+    if ladder.is_empty() {
+        ladder.extend(HLS_LADDER.last());
+    }
+
+    let mut filter = match burn_subtitles {
+        Some(subtitle_stream) => format!("{},split={}", burn_in_filter(&streams, subtitle_stream)?, ladder.len()),
+        None => format!("[0:v]split={}", ladder.len()),
+    };
+    for i in 0..ladder.len() {
+        filter.push_str(&format!("[v{}]", i));
+    }
+    for (i, (height, _)) in ladder.iter().enumerate() {
+        filter.push_str(&format!(";[v{i}]scale=-2:{height}[v{i}out]"));
+    }
+
+    let mut args: Vec<String> = vec!["-i".into(), input_path.into(), "-filter_complex".into(), filter];
+    let mut stream_map = Vec::new();
+    for (i, (height, bitrate)) in ladder.iter().enumerate() {
+        args.extend(["-map".into(), format!("[v{i}out]"), format!("-b:v:{i}"), bitrate.to_string()]);
+        if audio_tracks.is_empty() {
+            stream_map.push(format!("v:{i},name:{height}p"));
+        } else {
+            stream_map.push(format!("v:{i},agroup:audio,name:{height}p"));
         }
-        
-        #[cfg(not(unix))]
-        {
-            // On Windows, ffmpeg also exits with code 255 on Ctrl+C
-            if status.code() == Some(255) {
-                let _ = std::fs::remove_file(&tmp_output_path);
-                return Err(ConvertError::Interrupted);
+    }
+    // Each audio track is its own rendition in one group, so players can switch between them
+    for (i, track) in audio_tracks.iter().enumerate() {
+        args.extend(["-map".into(), format!("0:a:{i}")]);
+        let mut rendition = format!("a:{i},agroup:audio,name:audio_{i}");
+        if let Some(language) = track.tags.get("language") {
+            rendition.push_str(&format!(",language:{}", language));
+        }
+        if i == 0 {
+            rendition.push_str(",default:yes");
+        }
+        stream_map.push(rendition);
+    }
+
+    args.extend(["-c:v".into(), codec.into()]);
+    args.extend(encoder_args(codec).iter().map(|arg| arg.to_string()));
+    // Keyframes on segment boundaries, so every rendition can be switched between at any segment
+    args.extend(["-force_key_frames".into(), format!("expr:gte(t,n_forced*{})", HLS_SEGMENT_SECONDS)]);
+    if !audio_tracks.is_empty() {
+        args.extend(audio_filters.track_args(input_path, &streams, cancel, on_progress).await?);
+        args.extend(["-c:a".into(), "aac".into()]);
+    }
+
+    std::fs::create_dir_all(&tmp_output_dir)?;
+    let tmp_output_dir_str = tmp_output_dir.to_str().unwrap();
+
+    #[rustfmt::skip]
+    args.extend([
+        "-f".into(), "hls".into(),
+        "-hls_time".into(), HLS_SEGMENT_SECONDS.to_string(),
+        "-hls_playlist_type".into(), "vod".into(),
+        "-hls_segment_filename".into(), format!("{}/stream_%v_%03d.ts", tmp_output_dir_str),
+        "-master_pl_name".into(), HLS_MASTER_PLAYLIST.into(),
+        "-var_stream_map".into(), stream_map.join(" "),
+        format!("{}/stream_%v.m3u8", tmp_output_dir_str),
+    ]);
+
+    run_ffmpeg(&args, &tmp_output_dir, output_dir, streams.duration(), cancel, on_progress).await?;
+    Ok(streams)
+}
+
+/// Copies an MP4 with only one of its audio tracks (counting from 0), for browsers that can't switch tracks
+pub async fn extract_audio_track(input_path: &Path, output_path: &Path, track: usize, cancel: &CancellationToken) -> Result<(), ConvertError> {
+    let tmp_output_path = in_progress_path(output_path);
+
+    if tmp_output_path.exists() {
+        return Err(ConvertError::InProgress);
+    }
+
+    let audio_map = format!("0:a:{}", track);
+    #[rustfmt::skip]
+    let args = [
+        "-i", input_path.to_str().unwrap(),
+        "-map", "0:v:0",
+        "-map", &audio_map,
+        "-c", "copy",
+        "-movflags", "faststart",
+        "-f", "mp4",
+        tmp_output_path.to_str().unwrap(),
+    ];
+
+    run_ffmpeg(&args, &tmp_output_path, output_path, None, cancel, &|_| {}).await
+}
+
+/// Extensions of subtitle files next to a video that are picked up with it
+const SIDECAR_SUBTITLE_EXTENSIONS: [&str; 4] = ["srt", "vtt", "ass", "ssa"];
+
+/// A subtitle track, converted to WebVTT
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Subtitle {
+    /// File name of the WebVTT, within the video's subtitles dir
+    pub file_name: String,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    /// Whether it came from a file next to the original, rather than from within it
+    pub sidecar: bool,
+}
+
+/// Finds subtitle files that go with a video: ones next to it and named after it, like `film.srt` or `film.en.srt`.
+/// Returns each with the language from its name, if there is one.
+pub async fn find_sidecar_subtitles(video_path: &Path) -> Vec<(PathBuf, Option<String>)> {
+    let (Some(dir), Some(stem)) = (video_path.parent(), video_path.file_stem().and_then(|s| s.to_str())) else {
+        return Vec::new();
+    };
+    let prefix = format!("{}.", stem);
+
+    let mut sidecars = Vec::new();
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return sidecars;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        sidecars.extend(sidecar_subtitle(entry.path(), &prefix));
+    }
+    sidecars.sort();
+    sidecars
+}
+
+/// Checks whether a file is subtitles for the video whose name starts with `prefix` (its stem and a dot).
+/// Anything between that and the extension has to be a language, so `film.extended.srt` doesn't go with `film.mkv`.
+fn sidecar_subtitle(path: PathBuf, prefix: &str) -> Option<(PathBuf, Option<String>)> {
+    let rest = path.file_name()?.to_str()?.strip_prefix(prefix)?;
+    let (language, extension) = match rest.rsplit_once('.') {
+        Some((language, extension)) if is_language_tag(language) => (Some(language.to_string()), extension),
+        Some(_) => return None,
+        None => (None, rest),
+    };
+    SIDECAR_SUBTITLE_EXTENSIONS
+        .contains(&extension.to_ascii_lowercase().as_str())
+        .then_some((path, language))
+}
+
+/// Whether a name looks like a BCP 47 language tag, like `en`, `pt-BR` or `zh-Hant`
+fn is_language_tag(name: &str) -> bool {
+    let mut subtags = name.split('-');
+    let language = subtags.next().unwrap_or_default();
+    (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|subtag| (2..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// Name of the list of a video's subtitles within its subtitles dir, so a reused output gets them back
+pub const SUBTITLES_MANIFEST: &str = "subtitles.json";
+
+/// Reads back the subtitles listed in a subtitles dir, or none if it hasn't got a list
+pub fn read_subtitles_manifest(output_dir: &Path) -> Vec<Subtitle> {
+    std::fs::read(output_dir.join(SUBTITLES_MANIFEST))
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+/// Converts a video's text subtitles, both embedded and in files next to it, to WebVTT files in `output_dir`.
+/// Bitmap subtitles, and any track that fails to convert, are left out.
+pub async fn extract_subtitles(input_path: &Path, probe: &FfFormat, output_dir: &Path, cancel: &CancellationToken) -> Vec<Subtitle> {
+    let mut sources = Vec::new();
+    for (i, stream) in probe.subtitle_streams().enumerate() {
+        if stream.is_text_subtitle() {
+            let subtitle = Subtitle {
+                file_name: String::new(),
+                language: stream.tags.get("language").cloned(),
+                title: stream.tags.get("title").cloned(),
+                sidecar: false,
+            };
+            sources.push((input_path.to_path_buf(), format!("0:s:{}", i), subtitle));
+        }
+    }
+    for (path, language) in find_sidecar_subtitles(input_path).await {
+        let subtitle = Subtitle { file_name: String::new(), language, title: None, sidecar: true };
+        sources.push((path, "0:s:0".to_string(), subtitle));
+    }
+    if sources.is_empty() {
+        return Vec::new();
+    }
+
+    if let Err(err) = std::fs::create_dir_all(output_dir) {
+        log::error!("Couldn't create {}: {}", output_dir.display(), err);
+        return Vec::new();
+    }
+
+    let mut subtitles = Vec::new();
+    for (source, map, mut subtitle) in sources {
+        subtitle.file_name = format!("{}.vtt", subtitles.len());
+        let output_path = output_dir.join(&subtitle.file_name);
+        let tmp_output_path = in_progress_path(&output_path);
+
+        #[rustfmt::skip]
+        let args = [
+            "-i", source.to_str().unwrap(),
+            "-map", &map,
+            "-c:s", "webvtt",
+            "-f", "webvtt",
+            tmp_output_path.to_str().unwrap(),
+        ];
+        match run_ffmpeg(&args, &tmp_output_path, &output_path, None, cancel, &|_| {}).await {
+            Ok(()) => subtitles.push(subtitle),
+            Err(ConvertError::Interrupted) => break,
+            Err(err) => log::error!("Couldn't convert subtitles from {}: {}", source.display(), err),
+        }
+    }
+
+    let manifest = serde_json::to_vec(&subtitles).unwrap();
+    if let Err(err) = tokio::fs::write(output_dir.join(SUBTITLES_MANIFEST), manifest).await {
+        log::error!("Couldn't write {}: {}", output_dir.join(SUBTITLES_MANIFEST).display(), err);
+    }
+    subtitles
+}
+
+/// Name of the poster frame within a video's previews dir
+pub const POSTER_FILE: &str = "poster.jpg";
+
+/// Name of the sheet of seek preview thumbnails within a video's previews dir
+pub const SPRITE_FILE: &str = "sprite.jpg";
+
+/// Name of the WebVTT index into the sprite sheet within a video's previews dir
+pub const THUMBNAILS_FILE: &str = "thumbnails.vtt";
+
+/// Widest a poster frame is made
+const POSTER_MAX_WIDTH: u32 = 1280;
+
+/// How far into a video the poster frame is taken, as a fraction of its duration
+const POSTER_POSITION: f64 = 0.1;
+
+/// Width of each seek preview thumbnail
+const THUMBNAIL_WIDTH: u32 = 160;
+
+/// Most thumbnails made for one video; longer videos get them further apart
+const MAX_THUMBNAILS: u32 = 100;
+
+/// Thumbnails per row of the sprite sheet
+const SPRITE_COLUMNS: u32 = 10;
+
+/// Formats seconds as a WebVTT timestamp, `HH:MM:SS.mmm`
+fn vtt_timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02}.{:03}", millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000)
+}
+
+/// Makes a poster frame and a sprite sheet of seek preview thumbnails for a video, with a WebVTT index
+/// into the sprite sheet, in `output_dir`. Thumbnails are evenly spaced, at most a second apart.
+pub async fn generate_previews(input_path: &Path, probe: &FfFormat, output_dir: &Path, cancel: &CancellationToken) -> Result<(), ConvertError> {
+    let tmp_output_dir = in_progress_path(output_dir);
+
+    if tmp_output_dir.exists() {
+        return Err(ConvertError::InProgress);
+    }
+
+    let Some(duration) = probe.duration().filter(|duration| *duration > 0.0) else {
+        return Err(ConvertError::HandBrakeError("duration unknown".to_string()));
+    };
+    let Some(video) = probe.video() else {
+        return Err(ConvertError::HandBrakeError("no video stream found".to_string()));
+    };
+
+    // The index needs to know where each thumbnail is, so their height is worked out here rather than by ffmpeg
+    let thumbnail_height = match (video.width, video.height) {
+        (Some(width), Some(height)) if width > 0 => (THUMBNAIL_WIDTH * height / width).max(2) / 2 * 2,
+        _ => THUMBNAIL_WIDTH * 9 / 16,
+    };
+    let interval = (duration / MAX_THUMBNAILS as f64).max(1.0);
+    let count = ((duration / interval).ceil() as u32).clamp(1, MAX_THUMBNAILS);
+    let rows = count.div_ceil(SPRITE_COLUMNS);
+
+    std::fs::create_dir_all(&tmp_output_dir)?;
+    let result = async {
+        let input = input_path.to_str().unwrap();
+
+        let poster_path = tmp_output_dir.join(POSTER_FILE);
+        let tmp_poster_path = in_progress_path(&poster_path);
+        let poster_position = (duration * POSTER_POSITION).to_string();
+        let poster_filter = format!("scale=min(iw\\,{}):-2", POSTER_MAX_WIDTH);
+        #[rustfmt::skip]
+        let args = [
+            "-ss", &poster_position,
+            "-i", input,
+            "-frames:v", "1",
+            "-vf", &poster_filter,
+            "-q:v", "3",
+            "-f", "image2",
+            "-update", "1",
+            tmp_poster_path.to_str().unwrap(),
+        ];
+        run_ffmpeg(&args, &tmp_poster_path, &poster_path, None, cancel, &|_| {}).await?;
+
+        // Only keyframes are decoded, which is much quicker and close enough for a preview
+        let sprite_path = tmp_output_dir.join(SPRITE_FILE);
+        let tmp_sprite_path = in_progress_path(&sprite_path);
+        let sprite_filter = format!(
+            "fps=1/{},scale={}:{},tile={}x{}",
+            interval, THUMBNAIL_WIDTH, thumbnail_height, SPRITE_COLUMNS, rows
+        );
+        #[rustfmt::skip]
+        let args = [
+            "-skip_frame", "nokey",
+            "-i", input,
+            "-vf", &sprite_filter,
+            "-frames:v", "1",
+            "-q:v", "5",
+            "-f", "image2",
+            "-update", "1",
+            tmp_sprite_path.to_str().unwrap(),
+        ];
+        run_ffmpeg(&args, &tmp_sprite_path, &sprite_path, Some(duration), cancel, &|_| {}).await?;
+
+        let mut index = String::from("WEBVTT\n");
+        for i in 0..count {
+            let start = i as f64 * interval;
+            let end = (start + interval).min(duration);
+            let (x, y) = (i % SPRITE_COLUMNS * THUMBNAIL_WIDTH, i / SPRITE_COLUMNS * thumbnail_height);
+            index.push_str(&format!(
+                "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+                vtt_timestamp(start),
+                vtt_timestamp(end),
+                SPRITE_FILE,
+                x,
+                y,
+                THUMBNAIL_WIDTH,
+                thumbnail_height
+            ));
+        }
+        std::fs::write(tmp_output_dir.join(THUMBNAILS_FILE), index)?;
+        Ok::<(), ConvertError>(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => {
+            if output_dir.exists() {
+                remove_output(output_dir)?;
             }
+            std::fs::rename(&tmp_output_dir, output_dir)?;
+            Ok(())
         }
-        
-        let result = match status.code() {
-            Some(0) => Ok(()),
-            Some(code) => Err(ConvertError::HandBrakeError(format!(
-                "exited with code {}",
-                code
-            ))),
-            None => Err(ConvertError::HandBrakeError("crashed".to_string())),
-        };
+        Err(err) => {
+            let _ = remove_output(&tmp_output_dir);
+            Err(err)
+        }
+    }
+}
 
-        if result.is_ok() {
-            std::fs::rename(tmp_output_path, output_path)?;
-        } else {
-            std::fs::remove_file(tmp_output_path)?;
+/// Loudness that normalisation aims for: integrated loudness, true peak and loudness range, per EBU R128
+const LOUDNORM_TARGET: &str = "I=-16:TP=-1.5:LRA=11";
+
+/// Sample rate audio is put back to after normalisation, which works at 192kHz to find true peaks
+const LOUDNORM_SAMPLE_RATE: &str = "48000";
+
+/// RNN-based noise reduction settings
+#[derive(Debug, Clone, Default)]
+pub struct Denoise {
+    pub model: Model,
+    /// How much of the denoised audio is mixed into the output, from -1 to 1.
+    /// Negative values keep that much of the noise that was taken out instead.
+    pub mix: f64,
+}
+
+/// Filters applied to the audio of every conversion, in the order they're chained
+#[derive(Debug, Clone, Default)]
+pub struct AudioFilters {
+    /// RNN-based noise reduction
+    pub denoise: Option<Denoise>,
+    /// Two-pass EBU R128 loudness normalisation, so quiet and loud videos play at the same volume
+    pub normalize_loudness: bool,
+}
+
+/// What the first loudnorm pass measured about a track, as it prints it
+#[derive(Deserialize, Debug)]
+struct LoudnessMeasurement {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    target_offset: String,
+}
+
+impl AudioFilters {
+    pub fn is_empty(&self) -> bool {
+        self.denoise.is_none() && !self.normalize_loudness
+    }
+
+    /// Arguments that filter each audio track of `input_path` (as mapped, in order) with its own chain.
+    /// Normalising loudness means first measuring each track, after any denoising since that changes it.
+    /// If that fails the track is normalised in a single pass instead, which is less accurate.
+    async fn track_args(&self, input_path: &str, streams: &FfFormat, cancel: &CancellationToken, on_progress: &ProgressFn<'_>) -> Result<Vec<String>, ConvertError> {
+        let mut args = Vec::new();
+        for track in 0..streams.audio_streams().count() {
+            let mut chain = Vec::new();
+            if let Some(ref denoise) = self.denoise {
+                chain.push(format!("arnndn=m={}:mix={}", denoise.model.path().to_str().unwrap(), denoise.mix));
+            }
+            if self.normalize_loudness {
+                match measure_loudness(input_path, streams.duration(), track, &chain, cancel, on_progress).await {
+                    Ok(measured) => chain.push(format!(
+                        "loudnorm={}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true",
+                        LOUDNORM_TARGET, measured.input_i, measured.input_tp, measured.input_lra, measured.input_thresh, measured.target_offset
+                    )),
+                    Err(ConvertError::Interrupted) => return Err(ConvertError::Interrupted),
+                    Err(err) => {
+                        log::warn!("Couldn't measure loudness of {} track {}, normalising in one pass: {}", input_path, track, err);
+                        chain.push(format!("loudnorm={}", LOUDNORM_TARGET));
+                    }
+                }
+                chain.push(format!("aresample={}", LOUDNORM_SAMPLE_RATE));
+            }
+            if chain.is_empty() {
+                chain.push("anull".to_string());
+            }
+            args.extend([format!("-filter:a:{}", track), chain.join(",")]);
         }
-        result
+        Ok(args)
+    }
+}
+
+/// Runs loudnorm's measuring pass over an audio track (counting from 0), after the filters in `chain`.
+/// It reads the whole track, so it reports progress like a conversion does.
+async fn measure_loudness(input_path: &str, duration: Option<f64>, track: usize, chain: &[String], cancel: &CancellationToken, on_progress: &ProgressFn<'_>) -> Result<LoudnessMeasurement, ConvertError> {
+    let audio_map = format!("0:a:{}", track);
+    let mut filter = chain.to_vec();
+    filter.push(format!("loudnorm={}:print_format=json", LOUDNORM_TARGET));
+    let filter = filter.join(",");
+
+    #[rustfmt::skip]
+    let args = [
+        "-hide_banner", "-nostats",
+        "-progress", "pipe:1",
+        "-i", input_path,
+        "-map", &audio_map,
+        "-af", &filter,
+        "-f", "null", "-",
+    ];
+    println!("{:?}", args.join(" "));
+
+    let mut proc = Command::new("ffmpeg")
+        .args(args)
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stdout = proc.stdout.take().unwrap();
+    let progress = Progress { stage: Stage::MeasuringLoudness, duration, ..Default::default() };
+    let measuring = async {
+        let (read, output) = tokio::join!(report_progress(stdout, progress, on_progress), proc.wait_with_output());
+        read?;
+        output
+    };
+
+    // Nothing is written, so there's nothing to clean up by stopping it gracefully
+    let output = tokio::select! {
+        output = measuring => output?,
+        _ = cancel.cancelled() => return Err(ConvertError::Interrupted),
+    };
+
+    // ffmpeg exits with code 255 when it catches SIGINT
+    if output.status.code() == Some(255) {
+        return Err(ConvertError::Interrupted);
+    }
+    if !output.status.success() {
+        return Err(ConvertError::HandBrakeError(format!("loudness measurement exited with {}", output.status)));
+    }
+
+    // The measurement is the last thing printed, as a JSON object
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let json = stderr
+        .rfind('{')
+        .zip(stderr.rfind('}'))
+        .map(|(start, end)| &stderr[start..=end])
+        .ok_or_else(|| ConvertError::HandBrakeError("no loudness measurement in output".to_string()))?;
+    Ok(serde_json::from_str(json)?)
+}
+
+/// Encoder settings to go with `-c:v codec`
+fn encoder_args(codec: &str) -> Vec<&'static str> {
+    let mut args = Vec::new();
+    // VideoToolbox encoders don't support -preset, use -realtime instead
+    if codec.contains("videotoolbox") {
+        args.extend_from_slice(&["-realtime", "0"]);
     } else {
-        Err(ConvertError::HandBrakeError("no video stream found".to_string()))
+        args.extend_from_slice(&["-preset", "ultrafast"]);
     }
+    // Use hvc1 tag for HEVC to ensure QuickTime compatibility
+    if is_hevc(codec) {
+        args.extend_from_slice(&["-tag:v", "hvc1"]);
+    }
+    args
 }
 
-#[derive(Deserialize, Debug)]
-struct FfStream {
-    codec_name: Option<String>,
-    codec_type: String,
+fn is_hevc(codec: &str) -> bool {
+    codec.contains("hevc") || codec.contains("h265")
 }
 
-#[derive(Deserialize, Debug)]
-struct FfFormat {
-    streams: Vec<FfStream>,
+/// Removes a conversion output, which is either a file or (for HLS) a directory
+pub fn remove_output(path: &Path) -> std::io::Result<()> {
+    if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
 }
 
-impl FfFormat {
-    fn video(&self) -> Option<&FfStream> {
-        self.streams.iter().find(|s| s.codec_type == "video")
+/// Reads what ffmpeg writes with `-progress pipe:1` until it closes its stdout, passing on each update.
+/// It writes a block of key=value lines for each update, ending with a `progress` line.
+async fn report_progress(stdout: ChildStdout, mut progress: Progress, on_progress: &ProgressFn<'_>) -> std::io::Result<()> {
+    let mut lines = BufReader::new(stdout).lines();
+    while let Some(line) = lines.next_line().await? {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        match key {
+            // Despite the name, out_time_ms is in microseconds too
+            "out_time_us" | "out_time_ms" => {
+                if let Ok(us) = value.parse::<f64>() {
+                    progress.out_time = us / 1_000_000.0;
+                }
+            }
+            "speed" => {
+                progress.speed = value.trim().trim_end_matches('x').parse().ok();
+            }
+            "progress" => {
+                progress.update_estimates();
+                on_progress(progress.clone());
+            }
+            _ => {}
+        }
     }
+    Ok(())
 }
 
-async fn probe_file(path: &str) -> Result<FfFormat, ConvertError> {
-    let args = vec!["-v", "quiet", "-print_format", "json", "-show_format", "-show_streams", path];
+/// How long ffmpeg gets to stop after being asked to, before it's killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs ffmpeg, which writes to `tmp_output_path`, and moves the result to `output_path` if it succeeds.
+/// `duration` is the length of the input, used to work out how far through the conversion is.
+/// If `cancel` is triggered ffmpeg is asked to stop, and its output is removed.
+async fn run_ffmpeg<S: AsRef<str>>(args: &[S], tmp_output_path: &Path, output_path: &Path, duration: Option<f64>, cancel: &CancellationToken, on_progress: &ProgressFn<'_>) -> Result<(), ConvertError> {
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_ref()).collect();
 
     println!("{:?}", args.join(" "));
 
-    let proc = Command::new("ffprobe")
+    let mut proc = Command::new("ffmpeg")
+        .args(["-progress", "pipe:1", "-nostats"])
         .args(args)
         .kill_on_drop(true)
-        .stdout(std::process::Stdio::piped())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
         .spawn()?;
 
-    let output = proc.wait_with_output().await?;
+    // ffmpeg quits when it reads a `q`, finishing off its output rather than leaving it corrupt
+    let mut stdin = proc.stdin.take().unwrap();
+    let stopping = AtomicBool::new(false);
+    let stop = async {
+        cancel.cancelled().await;
+        stopping.store(true, Ordering::SeqCst);
+        let _ = stdin.write_all(b"q").await;
+        let _ = stdin.flush().await;
+        tokio::time::sleep(STOP_TIMEOUT).await;
+    };
+    tokio::pin!(stop);
+
+    let progress = Progress { duration, ..Default::default() };
+    tokio::select! {
+        read = report_progress(proc.stdout.take().unwrap(), progress, on_progress) => read?,
+        _ = &mut stop => {
+            log::warn!("ffmpeg didn't stop in time, killing it");
+            proc.start_kill()?;
+        }
+    }
+
+    let status = proc.wait().await?;
+
+    // What it wrote is incomplete, even if it exited cleanly
+    if stopping.load(Ordering::SeqCst) {
+        let _ = remove_output(tmp_output_path);
+        return Err(ConvertError::Interrupted);
+    }
+
+    #[cfg(unix)]
+    {
+        // Check if the process was killed by SIGINT (Ctrl+C)
+        // - signal() returns Some(2) if terminated by signal
+        // - ffmpeg exits with code 255 when it catches SIGINT internally
+        if status.signal() == Some(2) || status.code() == Some(255) {
+            // Clean up the temp output before propagating
+            let _ = remove_output(tmp_output_path);
+            return Err(ConvertError::Interrupted);
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        // On Windows, ffmpeg also exits with code 255 on Ctrl+C
+        if status.code() == Some(255) {
+            let _ = remove_output(tmp_output_path);
+            return Err(ConvertError::Interrupted);
+        }
+    }
 
-    let format: FfFormat = serde_json::from_slice(&output.stdout)?;
+    let result = match status.code() {
+        Some(0) => Ok(()),
+        Some(code) => Err(ConvertError::HandBrakeError(format!(
+            "exited with code {}",
+            code
+        ))),
+        None => Err(ConvertError::HandBrakeError("crashed".to_string())),
+    };
 
-    Ok(format)
+    if result.is_ok() {
+        std::fs::rename(tmp_output_path, output_path)?;
+    } else {
+        remove_output(tmp_output_path)?;
+    }
+    result
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sidecar_subtitles_need_a_language_between_name_and_extension() {
+        let sidecar = |name: &str| sidecar_subtitle(PathBuf::from(name), "film.").map(|(_, language)| language);
+        assert_eq!(sidecar("film.srt"), Some(None));
+        assert_eq!(sidecar("film.en.srt"), Some(Some("en".to_string())));
+        assert_eq!(sidecar("film.pt-BR.VTT"), Some(Some("pt-BR".to_string())));
+        assert_eq!(sidecar("film.zh-Hant.ass"), Some(Some("zh-Hant".to_string())));
+        assert_eq!(sidecar("film.extended.srt"), None);
+        assert_eq!(sidecar("film.en.forced.srt"), None);
+        assert_eq!(sidecar("film.en.txt"), None);
+        assert_eq!(sidecar("film.mkv"), None);
+        assert_eq!(sidecar("other.en.srt"), None);
+    }
+
+    #[test]
+    fn progress_estimates_fraction_and_time_left() {
+        let mut progress = Progress { out_time: 30.0, duration: Some(120.0), speed: Some(2.0), ..Default::default() };
+        progress.update_estimates();
+        assert_eq!(progress.fraction, Some(0.25));
+        assert_eq!(progress.eta, Some(45.0));
+
+        // ffmpeg can report a little past the probed duration
+        progress.out_time = 121.0;
+        progress.update_estimates();
+        assert_eq!(progress.fraction, Some(1.0));
+        assert_eq!(progress.eta, Some(0.0));
+    }
+
+    #[test]
+    fn progress_estimates_need_duration_and_speed() {
+        let mut progress = Progress { out_time: 30.0, duration: None, speed: Some(2.0), ..Default::default() };
+        progress.update_estimates();
+        assert_eq!((progress.fraction, progress.eta), (None, None));
+
+        progress.duration = Some(0.0);
+        progress.speed = Some(0.0);
+        progress.update_estimates();
+        assert_eq!((progress.fraction, progress.eta), (None, None));
+    }
+
+    #[test]
+    fn vtt_timestamp_formats_hours_minutes_seconds_and_millis() {
+        assert_eq!(vtt_timestamp(0.0), "00:00:00.000");
+        assert_eq!(vtt_timestamp(1.5), "00:00:01.500");
+        assert_eq!(vtt_timestamp(59.9996), "00:01:00.000");
+        assert_eq!(vtt_timestamp(3723.042), "01:02:03.042");
+    }
+}
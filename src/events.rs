@@ -0,0 +1,47 @@
+use serde::Serialize;
+
+/// A change to the queue, sent to clients subscribed to `/events`
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A file finished converting and can be played
+    Converted { id: String },
+    /// A file was deleted, or dropped from the queue
+    Deleted { id: String },
+    /// A file couldn't be converted
+    Failed { id: String, error: String },
+    /// A file was re-encoded and put back at the end of the queue
+    Reencoded { id: String },
+    /// A new file was found in the media dir
+    Added { id: String },
+    /// A file's original disappeared from the media dir
+    Removed { id: String },
+    /// A delete was undone, and the file is back where it was in the queue
+    Restored { id: String },
+    /// A file was kept, and dropped from the queue
+    Kept { id: String },
+    /// A playable file's subtitles and previews have been made, and `previews` says whether it has previews
+    ExtrasReady { id: String, previews: bool },
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Converted { .. } => "converted",
+            Event::Deleted { .. } => "deleted",
+            Event::Failed { .. } => "failed",
+            Event::Reencoded { .. } => "reencoded",
+            Event::Added { .. } => "added",
+            Event::Removed { .. } => "removed",
+            Event::Restored { .. } => "restored",
+            Event::Kept { .. } => "kept",
+            Event::ExtrasReady { .. } => "extras_ready",
+        }
+    }
+
+    /// Formats the event as a server-sent event
+    pub fn to_sse(&self) -> String {
+        let data = serde_json::to_string(self).unwrap();
+        format!("event: {}\ndata: {}\n\n", self.name(), data)
+    }
+}
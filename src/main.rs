@@ -1,15 +1,26 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 use actix_files::Files;
 use actix_web::{web, App, HttpResponse, HttpServer, ResponseError};
-use clap::Parser;
-use player::PlayerError;
+use clap::{CommandFactory, Parser};
+use convert::{AudioFilters, Denoise, OutputFormat};
+use order::Order;
+use player::{PlayerError, PlayerOptions};
+use rnnoise::Model;
+use state::StateError;
 use tokio::signal;
 
 mod convert;
+mod events;
+mod order;
 mod player;
+mod probe;
 mod rnnoise;
 mod routes;
+mod state;
+mod trash;
+mod watcher;
 
 #[derive(Parser, Debug)]
 #[command(name = "browser-video-player")]
@@ -27,6 +38,14 @@ struct Args {
     #[arg(short, long, default_value_t = 5)]
     buffer_count: usize,
 
+    /// Pause conversion while converted videos take up more than this many bytes
+    #[arg(long)]
+    cache_max_bytes: Option<u64>,
+
+    /// Number of videos to convert at the same time
+    #[arg(long, default_value_t = 1)]
+    workers: usize,
+
     /// Disable video deletion (delete requests will be ignored)
     #[arg(long, default_value_t = false)]
     no_delete: bool,
@@ -35,18 +54,108 @@ struct Args {
     #[arg(long, default_value_t = false)]
     always_reencode: bool,
 
+    /// Convert every video, even ones browsers can already play as they are
+    #[arg(long, default_value_t = false)]
+    no_direct_serve: bool,
+
     /// Apply RNN-based noise reduction to audio (reduces background noise)
     #[arg(long, default_value_t = false)]
     denoise: bool,
+
+    /// How much of the denoised audio to mix in, from -1 to 1 (negative values keep the removed noise instead)
+    #[arg(long, default_value_t = 0.5, requires = "denoise", value_parser = parse_denoise_mix)]
+    denoise_mix: f64,
+
+    /// RNNoise model to denoise with: a path to an .rnnn file, or the name of a bundled one
+    /// (somnolent-hogwash, beguiling-drafter, conjoined-burgers, leavened-quisling or marathon-prescription)
+    #[arg(long, default_value = rnnoise::DEFAULT_MODEL, requires = "denoise")]
+    denoise_model: Model,
+
+    /// Normalise audio loudness (EBU R128), so quiet and loud videos play at the same volume.
+    /// Each audio track is first read through to measure it, which delays the start of every conversion
+    /// (and of playing it with --progressive); /conversions shows these passes as measuring_loudness
+    #[arg(long, default_value_t = false)]
+    normalize_loudness: bool,
+
+    /// Start playing videos while they're still being converted (MP4 output only, produces fragmented MP4)
+    #[arg(long, default_value_t = false)]
+    progressive: bool,
+
+    /// Output format for converted videos
+    #[arg(long, value_enum, default_value_t = OutputFormat::Mp4)]
+    output: OutputFormat,
+
+    /// Order to play videos in
+    #[arg(long, value_enum, default_value_t = Order::Random)]
+    order: Order,
+
+    /// Directory to keep the queue and converted videos in, so they survive a restart
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+
+    /// Directory to keep converted videos in, reusing them on restart if the original hasn't changed.
+    /// They go in a browser-video-player dir within it, so it can be shared with other things
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Move deleted videos into this directory instead of deleting them
+    #[arg(long)]
+    trash_dir: Option<PathBuf>,
+
+    /// Days to keep deleted videos in the trash before deleting them for good
+    #[arg(long, default_value_t = 30)]
+    trash_retention_days: u64,
+
+    /// Seconds a delete can be undone for before it goes through (0 deletes immediately)
+    #[arg(long, default_value_t = 10)]
+    undo_seconds: u64,
+
+    /// Move kept videos into this directory (optionally into a named collection within it).
+    /// Keeping videos needs either this or --state-dir.
+    #[arg(long)]
+    kept_dir: Option<PathBuf>,
+
+    /// Play videos with this tag sooner (can be given more than once)
+    #[arg(long)]
+    prefer_tag: Vec<String>,
+
+    /// Play videos with this tag later (can be given more than once)
+    #[arg(long)]
+    avoid_tag: Vec<String>,
+
+    /// Play videos that have already been watched after ones that haven't
+    #[arg(long, default_value_t = false)]
+    unseen_first: bool,
+
+    /// Don't watch the media directory for new and removed videos
+    #[arg(long, default_value_t = false)]
+    no_watch: bool,
 }
 
 impl ResponseError for PlayerError {
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::InternalServerError().finish()
+        HttpResponse::build(self.status_code()).finish()
     }
 
+    /// Moving a file onto one that's already there is refused rather than overwriting it, which is a conflict
     fn status_code(&self) -> actix_web::http::StatusCode {
-        actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
+        let io_error = match self {
+            PlayerError::IoError(err) | PlayerError::StateError(StateError::IOError(err)) => Some(err),
+            _ => None,
+        };
+        match io_error {
+            Some(err) if err.kind() == std::io::ErrorKind::AlreadyExists => actix_web::http::StatusCode::CONFLICT,
+            _ => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+fn parse_denoise_mix(s: &str) -> Result<f64, String> {
+    let mix: f64 = s.parse().map_err(|err| format!("{}", err))?;
+    if (-1.0..=1.0).contains(&mix) {
+        Ok(mix)
+    } else {
+        Err("must be from -1 to 1".to_string())
     }
 }
 
@@ -55,13 +164,57 @@ async fn main() -> std::io::Result<()> {
     env_logger::init();
 
     let args = Args::parse();
+    if args.progressive && args.output != OutputFormat::Mp4 {
+        Args::command()
+            .error(clap::error::ErrorKind::ArgumentConflict, "--progressive only works with --output mp4")
+            .exit();
+    }
+
+    // The watcher reports absolute paths, so the queue has to use them too
+    let media_dir = args.path.canonicalize()?;
 
-    let player = web::Data::new(player::Player::new(&args.path, args.codec.as_deref(), args.buffer_count, args.no_delete, args.always_reencode, args.denoise));
+    let options = PlayerOptions {
+        codec: args.codec.clone(),
+        buffer_count: args.buffer_count,
+        workers: args.workers,
+        no_delete: args.no_delete,
+        always_reencode: args.always_reencode,
+        direct_serve: !args.no_direct_serve,
+        audio_filters: AudioFilters {
+            denoise: args.denoise.then(|| Denoise { model: args.denoise_model.clone(), mix: args.denoise_mix }),
+            normalize_loudness: args.normalize_loudness,
+        },
+        progressive: args.progressive,
+        output: args.output,
+        order: args.order,
+        state_dir: args.state_dir.clone(),
+        cache_dir: args.cache_dir.clone(),
+        trash_dir: args.trash_dir.clone(),
+        trash_retention: Duration::from_secs(args.trash_retention_days * 24 * 60 * 60),
+        undo_window: Duration::from_secs(args.undo_seconds),
+        kept_dir: args.kept_dir.clone(),
+        prefer_tags: args.prefer_tag.clone(),
+        avoid_tags: args.avoid_tag.clone(),
+        unseen_first: args.unseen_first,
+        cache_max_bytes: args.cache_max_bytes,
+    };
+    // Check the model before converting anything, rather than failing every conversion
+    if args.denoise {
+        args.denoise_model.validate().await.map_err(std::io::Error::other)?;
+    }
+
+    let player = player::Player::new(&media_dir, options).map_err(std::io::Error::other)?;
+    let player = web::Data::new(player);
     let files_dir: String = player.files_dir().to_str().unwrap().to_string();
 
     log::info!("Serving static files from: {}", &files_dir);
 
     let conversion_player = player.clone();
+    let watch_player = player.clone();
+    let server_player = player.clone();
+    let trash_player = player.clone();
+    let delete_player = player.clone();
+    let probe_player = player.clone();
 
     let server = HttpServer::new(move || {
         App::new()
@@ -69,14 +222,41 @@ async fn main() -> std::io::Result<()> {
             .service(Files::new("/video-files", &files_dir))
             .service(routes::get_random)
             .service(routes::delete_video)
+            .service(routes::undo_delete)
+            .service(routes::keep_video)
             .service(routes::reencode_video)
+            .service(routes::stream_video)
+            .service(routes::get_original)
+            .service(routes::get_audio_track)
+            .service(routes::get_conversions)
+            .service(routes::get_cache)
+            .service(routes::get_events)
+            .service(routes::list_videos)
+            .service(routes::get_video_info)
+            .service(routes::get_subtitles)
+            .service(routes::get_preview)
+            .service(routes::get_video_meta)
+            .service(routes::set_video_meta)
+            .service(routes::record_progress)
+            .service(routes::get_history)
+            .service(routes::set_order)
+            .service(routes::list_trash)
+            .service(routes::restore_from_trash)
             .service(routes::get_root)
+            .service(routes::get_hls_js)
     })
     .bind(("0.0.0.0", 8081))?
     .run();
 
     let server_handle = server.handle();
 
+    let server = async {
+        let result = server.await;
+        // The server also stops itself on SIGTERM, so take everything else down with it
+        server_player.cancel();
+        result
+    };
+
     let conversion = async {
         let result = conversion_player.convert_all().await;
         if let Err(ref err) = result {
@@ -87,15 +267,71 @@ async fn main() -> std::io::Result<()> {
         result.map_err(std::io::Error::other)
     };
 
+    let watch = async {
+        if args.no_watch {
+            return Ok(());
+        }
+        watcher::watch(&watch_player, &media_dir)
+            .await
+            .map_err(std::io::Error::other)
+    };
+
+    let purge_trash = async {
+        trash_player.purge_trash().await;
+        Ok(())
+    };
+
+    let pending_deletes = async {
+        delete_player.run_pending_deletes().await;
+        Ok(())
+    };
+
+    let state_saves = async {
+        delete_player.run_state_saves().await;
+        Ok(())
+    };
+
+    let probe_durations = async {
+        probe_player.probe_durations().await;
+        Ok(())
+    };
+
     let ctrl_c = async {
-        signal::ctrl_c().await.expect("Failed to listen for Ctrl+C");
-        log::info!("Ctrl+C received, shutting down...");
-        conversion_player.cancel();
-        server_handle.stop(false).await;
+        tokio::select! {
+            result = signal::ctrl_c() => {
+                result.expect("Failed to listen for Ctrl+C");
+                log::info!("Ctrl+C received, shutting down...");
+                conversion_player.cancel();
+                server_handle.stop(false).await;
+            }
+            _ = conversion_player.cancelled() => {}
+        }
         Ok(())
     };
 
-    tokio::try_join!(server, conversion, ctrl_c)?;
+    let result = tokio::try_join!(server, conversion, watch, purge_trash, pending_deletes, state_saves, probe_durations, ctrl_c);
+
+    // Deletes still waiting on their undo window go through rather than being forgotten
+    delete_player.flush_pending_deletes().await;
+    // Likewise changes to the state that haven't been written out yet
+    delete_player.flush_state().map_err(std::io::Error::other)?;
+    result?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn denoise_mix_must_be_a_number_from_minus_one_to_one() {
+        assert_eq!(parse_denoise_mix("0.5"), Ok(0.5));
+        assert_eq!(parse_denoise_mix("-1"), Ok(-1.0));
+        assert_eq!(parse_denoise_mix("1"), Ok(1.0));
+        assert!(parse_denoise_mix("1.01").is_err());
+        assert!(parse_denoise_mix("-2").is_err());
+        assert!(parse_denoise_mix("NaN").is_err());
+        assert!(parse_denoise_mix("loud").is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::player::File;
+use crate::state::VideoMeta;
+
+/// The order files are played in
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    /// Shuffled
+    Random,
+    /// By path, alphabetically
+    Name,
+    /// Most recently modified first
+    Newest,
+    /// Least recently modified first
+    Oldest,
+    /// Largest first
+    Size,
+    /// Shortest first; files that haven't been probed yet go last
+    Duration,
+    /// Alternating between folders, each in name order
+    Folder,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn size(path: &Path) -> u64 {
+    std::fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Sorts files into the given order
+pub fn sort_files(files: &mut Vec<File>, order: Order) {
+    match order {
+        Order::Random => files.sort_by_cached_key(|_| Uuid::new_v4()),
+        Order::Name => files.sort_by(|a, b| a.original_path.cmp(&b.original_path)),
+        // Files that can't be read sort as if they're the oldest
+        Order::Newest => files.sort_by_cached_key(|f| Reverse(modified(&f.original_path))),
+        Order::Oldest => files.sort_by_cached_key(|f| modified(&f.original_path).unwrap_or(SystemTime::UNIX_EPOCH)),
+        Order::Size => files.sort_by_cached_key(|f| Reverse(size(&f.original_path))),
+        Order::Duration => files.sort_by(|a, b| {
+            let duration = |f: &File| f.info.as_ref().and_then(|info| info.duration).unwrap_or(f64::INFINITY);
+            duration(a).total_cmp(&duration(b))
+        }),
+        Order::Folder => {
+            let mut folders: BTreeMap<PathBuf, Vec<File>> = BTreeMap::new();
+            for file in files.drain(..) {
+                let folder = file.original_path.parent().map(Path::to_path_buf).unwrap_or_default();
+                folders.entry(folder).or_default().push(file);
+            }
+            let mut folders: Vec<_> = folders
+                .into_values()
+                .map(|mut folder| {
+                    folder.sort_by(|a, b| a.original_path.cmp(&b.original_path));
+                    folder.into_iter()
+                })
+                .collect();
+            // Take one from each folder in turn until they're all empty
+            while !folders.is_empty() {
+                folders.retain_mut(|folder| match folder.next() {
+                    Some(file) => {
+                        files.push(file);
+                        true
+                    }
+                    None => false,
+                });
+            }
+        }
+    }
+}
+
+/// Moves files ahead of or behind the rest of the queue, whatever the order
+#[derive(Debug, Default)]
+pub struct Bias {
+    /// Files with any of these tags go ahead
+    pub prefer_tags: Vec<String>,
+    /// Files with any of these tags go behind
+    pub avoid_tags: Vec<String>,
+    /// Files that have already been watched go behind ones that haven't
+    pub unseen_first: bool,
+}
+
+impl Bias {
+    pub fn is_empty(&self) -> bool {
+        self.prefer_tags.is_empty() && self.avoid_tags.is_empty() && !self.unseen_first
+    }
+
+    fn tag_weight(&self, meta: Option<&VideoMeta>) -> i32 {
+        let Some(meta) = meta else {
+            return 0;
+        };
+        let preferred = self.prefer_tags.iter().any(|tag| meta.has_tag(tag));
+        let avoided = self.avoid_tags.iter().any(|tag| meta.has_tag(tag));
+        preferred as i32 - avoided as i32
+    }
+
+    /// Moves files ahead or behind, otherwise keeping their order.
+    /// Files are never moved ahead of ones that have been started (converted or being converted),
+    /// so the buffer is still played first.
+    pub fn apply<'a>(
+        &self,
+        files: &mut [File],
+        is_started: impl Fn(&File) -> bool,
+        meta: impl Fn(&File) -> Option<&'a VideoMeta>,
+        is_seen: impl Fn(&File) -> bool,
+    ) {
+        if self.is_empty() {
+            return;
+        }
+        files.sort_by_cached_key(|f| {
+            (!is_started(f), self.unseen_first && is_seen(f), Reverse(self.tag_weight(meta(f))))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::MediaInfo;
+
+    fn file(path: &str, duration: Option<f64>) -> File {
+        let mut file = File::new(PathBuf::from(path));
+        file.info = duration.map(|duration| MediaInfo { duration: Some(duration), ..Default::default() });
+        file
+    }
+
+    fn paths(files: &[File]) -> Vec<&str> {
+        files.iter().map(|f| f.original_path.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn name_order_sorts_by_path_components() {
+        let mut files = vec![file("/m/b.mkv", None), file("/m/a.mkv", None), file("/m/a/z.mkv", None)];
+        sort_files(&mut files, Order::Name);
+        assert_eq!(paths(&files), ["/m/a/z.mkv", "/m/a.mkv", "/m/b.mkv"]);
+    }
+
+    #[test]
+    fn duration_order_puts_unprobed_files_last() {
+        let mut files = vec![
+            file("/m/long.mkv", Some(3600.0)),
+            file("/m/unprobed.mkv", None),
+            file("/m/short.mkv", Some(60.0)),
+            file("/m/medium.mkv", Some(600.0)),
+        ];
+        sort_files(&mut files, Order::Duration);
+        assert_eq!(paths(&files), ["/m/short.mkv", "/m/medium.mkv", "/m/long.mkv", "/m/unprobed.mkv"]);
+    }
+
+    #[test]
+    fn folder_order_alternates_between_folders() {
+        let mut files = vec![
+            file("/m/b/2.mkv", None),
+            file("/m/a/1.mkv", None),
+            file("/m/b/1.mkv", None),
+            file("/m/a/2.mkv", None),
+            file("/m/a/3.mkv", None),
+        ];
+        sort_files(&mut files, Order::Folder);
+        assert_eq!(paths(&files), ["/m/a/1.mkv", "/m/b/1.mkv", "/m/a/2.mkv", "/m/b/2.mkv", "/m/a/3.mkv"]);
+    }
+
+    #[test]
+    fn size_order_puts_largest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = Vec::new();
+        for (name, size) in [("small.mkv", 1), ("large.mkv", 100), ("medium.mkv", 10)] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, vec![0; size]).unwrap();
+            files.push(File::new(path));
+        }
+        sort_files(&mut files, Order::Size);
+        let names: Vec<_> = files.iter().map(|f| f.original_path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["large.mkv", "medium.mkv", "small.mkv"]);
+    }
+
+    #[test]
+    fn random_order_keeps_every_file() {
+        let mut files: Vec<_> = (0..20).map(|i| file(&format!("/m/{}.mkv", i), None)).collect();
+        sort_files(&mut files, Order::Random);
+        let mut sorted = paths(&files);
+        sorted.sort();
+        let mut expected: Vec<_> = (0..20).map(|i| format!("/m/{}.mkv", i)).collect();
+        expected.sort();
+        assert_eq!(sorted, expected);
+    }
+}
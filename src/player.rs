@@ -1,37 +1,96 @@
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
     sync::Mutex,
+    time::{Duration, Instant},
 };
 
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
 use tempfile::TempDir;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 use walkdir::{WalkDir, DirEntry};
 
-use crate::convert::{convert_to_mp4, ConvertError};
+use crate::convert::{convert_to_hls, convert_to_mp4, extract_audio_track, extract_subtitles, find_sidecar_subtitles, generate_previews, read_subtitles_manifest, in_progress_path, Subtitle, remove_output, AudioFilters, ConvertError, Mp4Options, OutputFormat, Progress, HLS_MASTER_PLAYLIST, POSTER_FILE, SPRITE_FILE, THUMBNAILS_FILE};
+use crate::probe::{has_first_fragment, is_faststart, probe_file, FfFormat, MediaInfo};
+use crate::events::Event;
+use crate::order::{sort_files, Bias, Order};
+use crate::trash::{move_file, now, Trash};
+use crate::state::{HistoryEntry, KeptFile, State, StateError, StoredFile, VideoMeta};
+
+/// How often to check the trash for videos that have been there long enough to purge
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Most videos to remember playing; the least recently played are forgotten first
+const HISTORY_LIMIT: usize = 1000;
+
+/// Fraction of a video that has to be played for it to count as watched
+const WATCHED_FRACTION: f64 = 0.9;
+
+/// Fraction of a video under which it counts as skipped
+const SKIPPED_FRACTION: f64 = 0.1;
+
+/// Extensions of originals that can be served as they are, if what's in them is playable
+const DIRECT_EXTENSIONS: [&str; 2] = ["mp4", "m4v"];
+
+/// Number of hex digits in a cache key
+const CACHE_KEY_LENGTH: usize = 32;
+
+/// Name of the dir within a cache dir that outputs go in. A cache dir might be shared with other things,
+/// so only this is cleaned up and served.
+const CACHE_SUBDIR: &str = "browser-video-player";
+
+/// Name of the dir within the files dir that single audio track copies go in.
+/// The files dir is always the player's own, so it can be emptied on startup.
+const TRACKS_DIR: &str = "tracks";
+
+/// Number of files probed at once for their duration, when ordering by it
+const PROBE_CONCURRENCY: usize = 4;
+
+/// Number of files probed for their duration between rearranging the queue
+const PROBE_BATCH: usize = 32;
+
+/// How often to check for deletes that can no longer be undone
+const PENDING_DELETE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest playback progress goes unsaved; players report it every few seconds, which is too often to write it out
+const PROGRESS_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait after the state changes before writing it out, so further changes go out with it
+const STATE_SAVE_DELAY: Duration = Duration::from_millis(500);
 
 const VIDEO_EXTENSIONS: [&str; 11] = ["mp4", "mkv", "avi", "mpg", "wmv", "webm", "ts", "mov", "flv", "f4v", "m4v"];
 
-fn get_media_files(path: &Path) -> impl Iterator<Item = DirEntry> {
+pub fn is_media_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_ascii_lowercase().to_str().map(|ext| VIDEO_EXTENSIONS.contains(&ext)))
+        .unwrap_or(false)
+}
+
+pub fn get_media_files(path: &Path) -> impl Iterator<Item = DirEntry> {
     WalkDir::new(path)
         .into_iter()
         .filter_map(|entry| entry.ok())
-        .filter(|entry| {
-            entry.file_type().is_file()
-                && entry.path().extension().is_some()
-                && VIDEO_EXTENSIONS
-                    .contains(&entry.path().extension().unwrap().to_ascii_lowercase().to_str().unwrap())
-        })
+        .filter(|entry| entry.file_type().is_file() && is_media_file(entry.path()))
 }
 
 #[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum PlayerError {
     #[error("Convert Error: {0}")]
     ConvertError(#[from] ConvertError),
 
     #[error("IO Error: {0}")]
     IoError(#[from] std::io::Error),
+
+    #[error("State Error: {0}")]
+    StateError(#[from] StateError),
+
+    #[error("Watch Error: {0}")]
+    WatchError(#[from] notify::Error),
 }
 
 #[derive(Debug, Clone)]
@@ -39,54 +98,847 @@ pub struct File {
     pub id: String,
     pub original_path: PathBuf,
     pub path: Option<PathBuf>,
+    /// Whether the original is already playable in a browser, so it's served as it is instead of being converted
+    pub direct: bool,
+    /// Subtitles converted to WebVTT, in the file's subtitles dir
+    pub subtitles: Vec<Subtitle>,
+    /// Whether a poster and seek previews have been made, in the file's previews dir
+    pub previews: bool,
+    /// What ffprobe found, once it's been probed
+    pub info: Option<MediaInfo>,
+    /// Why the file couldn't be converted, if it failed
+    pub error: Option<String>,
+    /// Why it couldn't be converted before the player restarted. It's tried again, and this is cleared
+    /// once it is; until then it's kept so it can still be looked up.
+    pub previous_error: Option<String>,
+    /// Whether the video was asked to be re-encoded even though it could be copied
+    pub force_reencode: bool,
+    /// Image-based subtitle stream (counting from 0) drawn onto the video
+    pub burn_subtitles: Option<usize>,
+    /// What its outputs in a cache dir are named after, fixed when it starts converting
+    /// so they're still found if the original changes or goes
+    pub output_name: Option<String>,
+}
+
+impl File {
+    pub(crate) fn new(original_path: PathBuf) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            original_path,
+            path: None,
+            direct: false,
+            subtitles: Vec::new(),
+            previews: false,
+            info: None,
+            error: None,
+            previous_error: None,
+            force_reencode: false,
+            burn_subtitles: None,
+            output_name: None,
+        }
+    }
+
+    /// Whether the file's ready to play without any more conversion
+    pub fn is_converted(&self) -> bool {
+        self.path.is_some() || self.direct
+    }
+}
+
+/// Where a file is in the conversion process
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileState {
+    Pending,
+    Converting,
+    Converted,
+    Failed,
+}
+
+/// How much of a video was played
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayStatus {
+    Skipped,
+    Partial,
+    Watched,
+}
+
+impl PlayStatus {
+    /// Works out how much of a video was played. If its duration isn't known, it's only ever partly played.
+    pub fn of(entry: &HistoryEntry) -> Self {
+        let Some(duration) = entry.duration.filter(|d| *d > 0.0) else {
+            return PlayStatus::Partial;
+        };
+        let fraction = entry.position / duration;
+        if fraction >= WATCHED_FRACTION {
+            PlayStatus::Watched
+        } else if fraction < SKIPPED_FRACTION {
+            PlayStatus::Skipped
+        } else {
+            PlayStatus::Partial
+        }
+    }
+}
+
+/// A conversion that's currently running
+#[derive(Serialize, Debug, Clone)]
+pub struct Conversion {
+    pub id: String,
+    /// Path of the original, relative to the media dir
+    pub original_path: PathBuf,
+    #[serde(flatten)]
+    pub progress: Progress,
+}
+
+/// The entry directly within `files_dir` that holds a converted output:
+/// the output itself for MP4, or its directory for HLS
+fn output_root(files_dir: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix(files_dir).ok().and_then(|p| p.components().next()) {
+        Some(root) => files_dir.join(root),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Size of an entry in the files dir: a file, or everything in a directory
+fn entry_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Sorts the queue, leaving files that are converted or being converted at the front
+/// so the buffer is still used first
+fn arrange(files: &mut Vec<File>, order: Order, converting: &HashMap<String, Conversion>) {
+    let (mut started, mut rest): (Vec<_>, Vec<_>) = files
+        .drain(..)
+        .partition(|f| f.is_converted() || converting.contains_key(&f.id));
+    sort_files(&mut rest, order);
+    started.extend(rest);
+    *files = started;
+}
+
+/// Which file an undone delete goes back after: the one it came after, or if that's waiting to be deleted too,
+/// what that came after, and so on. `None` means the front of the queue.
+fn undone_after(pending_deletes: &HashMap<String, PendingDelete>, mut after: Option<String>) -> Option<String> {
+    while let Some(previous) = after.as_ref().and_then(|after| pending_deletes.get(after)) {
+        after = previous.after.clone();
+    }
+    after
+}
+
+/// Puts an undone delete back in the queue after the file with id `after`, or where it was (`index`)
+/// if that's gone. Unless the queue is shuffled, it's then put back in order.
+fn reinsert(files: &mut Vec<File>, file: File, after: Option<&str>, index: usize, order: Order, converting: &HashMap<String, Conversion>) {
+    let position = match after {
+        Some(after) => files.iter().position(|f| f.id == after).map(|i| i + 1),
+        None => Some(0),
+    };
+    files.insert(position.unwrap_or(index.min(files.len())), file);
+    if order != Order::Random {
+        arrange(files, order, converting);
+    }
+}
+
+/// Fails if `dir` is inside the media dir, where the watcher would find the videos in it and queue them again.
+/// `what` names the dir in the error.
+fn check_outside_media_dir(dir: &Path, what: &str, media_dir: &Path) -> std::io::Result<()> {
+    if dir.canonicalize()?.starts_with(media_dir) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} {} is inside the media dir", what, dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether a file has been kept, and so shouldn't be queued:
+/// either it's been filed into the kept dir, or it was kept where it was
+fn is_kept(path: &Path, media_dir: &Path, kept_dir: Option<&Path>, kept: &[KeptFile]) -> bool {
+    if kept_dir.is_some_and(|dir| path.starts_with(dir)) {
+        return true;
+    }
+    let relative_path = path.strip_prefix(media_dir).unwrap_or(path);
+    kept.iter().any(|k| k.original_path == relative_path)
+}
+
+/// Whether the file with this original, relative to the media dir, has been watched
+fn is_seen(history: &[HistoryEntry], original_path: &Path) -> bool {
+    history.iter().any(|h| h.original_path == original_path && PlayStatus::of(h) == PlayStatus::Watched)
+}
+
+/// Rebuilds the queue from the stored state, reconciled against what's actually on disk.
+/// Stored files keep their id, position and converted output; files that have disappeared are dropped
+/// and newly found files are shuffled onto the end of the queue. Kept files are left out.
+fn restore_queue(media_dir: &Path, files_dir: &Path, stored_files: Vec<StoredFile>, is_kept: impl Fn(&Path) -> bool) -> Vec<File> {
+    let mut found: HashSet<PathBuf> = get_media_files(media_dir)
+        .map(|entry| entry.into_path())
+        .filter(|path| !is_kept(path))
+        .collect();
+
+    let mut files: Vec<_> = stored_files
+        .into_iter()
+        .filter_map(|stored| {
+            let original_path = media_dir.join(&stored.original_path);
+            if !found.remove(&original_path) {
+                return None;
+            }
+            let path = stored.path.map(|p| files_dir.join(p)).filter(|p| p.exists());
+            Some(File {
+                id: stored.id,
+                original_path,
+                path,
+                direct: stored.direct,
+                subtitles: stored.subtitles,
+                previews: stored.previews,
+                info: stored.info,
+                // A failure may have been down to something that's since been fixed, so it's tried again
+                error: None,
+                previous_error: stored.error,
+                force_reencode: stored.force_reencode,
+                burn_subtitles: stored.burn_subtitles,
+                output_name: stored.output_name,
+            })
+        })
+        .collect();
+
+    let mut new_files: Vec<_> = found
+        .into_iter()
+        .map(File::new)
+        .collect();
+    new_files.sort_by_key(|f| f.id.clone());
+    files.extend(new_files);
+    files
+}
+
+/// Hashes what identifies a conversion: the original's path relative to the media dir, its modification time
+/// and size, and `settings`. Returns `None` if the original can't be read.
+fn cache_key(original_path: &Path, relative_path: &Path, settings: &str) -> Option<String> {
+    let metadata = std::fs::metadata(original_path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?;
+    let identity = format!("{}\n{}\n{}\n{}", relative_path.display(), modified.as_nanos(), metadata.len(), settings);
+    let hash = blake3::hash(identity.as_bytes()).to_hex();
+    Some(hash[..CACHE_KEY_LENGTH].to_string())
+}
+
+/// Where a copy of a file with only one of its audio tracks is at
+pub enum AudioTrack {
+    Ready(PathBuf),
+    /// Not made yet, so the caller should make it
+    Make { source: PathBuf, output: PathBuf },
+    /// Being made by an earlier request
+    Making,
+}
+
+/// A deleted file that can still be undone
+struct PendingDelete {
+    file: File,
+    /// Id of the file it came after in the queue, or `None` if it was first
+    after: Option<String>,
+    /// Where it was in the queue, for if the file it came after has gone too
+    index: usize,
+    keep_original: bool,
+    /// When the delete goes through
+    deadline: Instant,
+}
+
+/// Settings for the player, as given on the command line
+pub struct PlayerOptions {
+    pub codec: Option<String>,
+    pub buffer_count: usize,
+    pub workers: usize,
+    pub no_delete: bool,
+    pub always_reencode: bool,
+    pub audio_filters: AudioFilters,
+    pub progressive: bool,
+    pub output: OutputFormat,
+    pub order: Order,
+    pub state_dir: Option<PathBuf>,
+    /// Keep converted files here, named so they can be reused across runs
+    pub cache_dir: Option<PathBuf>,
+    /// Move deleted originals here instead of deleting them
+    pub trash_dir: Option<PathBuf>,
+    /// How long deleted originals stay in the trash
+    pub trash_retention: Duration,
+    /// How long a delete can be undone for before it goes through
+    pub undo_window: Duration,
+    /// Move kept originals here, instead of leaving them where they are
+    pub kept_dir: Option<PathBuf>,
+    /// Move files with these tags ahead in the queue
+    pub prefer_tags: Vec<String>,
+    /// Move files with these tags to the back of the queue
+    pub avoid_tags: Vec<String>,
+    /// Move files that have been watched to the back of the queue
+    pub unseen_first: bool,
+    /// Stop starting conversions once converted files take up this much space
+    pub cache_max_bytes: Option<u64>,
+    /// Serve originals that are already playable in a browser as they are, instead of converting them
+    pub direct_serve: bool,
 }
 
 pub struct Player {
     media_dir: PathBuf,
-    tmp_dir: TempDir,
+    files_dir: PathBuf,
+    /// Keeps the temp dir alive when there's no state dir to store converted files in
+    _tmp_dir: Option<TempDir>,
+    /// Where single audio track copies go; emptied on startup
+    tracks_dir: PathBuf,
+    /// Single audio track copies being made
+    making_tracks: Mutex<HashSet<PathBuf>>,
+    /// Whether outputs are named by a key of their original and the conversion settings (in a cache dir),
+    /// rather than by id
+    keyed_outputs: bool,
+    state_path: Option<PathBuf>,
     files: Mutex<Vec<File>>,
+    /// Files currently being converted, by id
+    converting: Mutex<HashMap<String, Conversion>>,
     codec: Option<String>,
     buffer_count: usize,
+    cache_max_bytes: Option<u64>,
+    workers: usize,
     no_delete: bool,
     always_reencode: bool,
-    denoise: bool,
+    direct_serve: bool,
+    audio_filters: AudioFilters,
+    progressive: bool,
+    output: OutputFormat,
+    order: Mutex<Order>,
+    trash: Option<Trash>,
+    undo_window: Duration,
+    /// Deletes waiting out the undo window, by id
+    pending_deletes: Mutex<HashMap<String, PendingDelete>>,
+    kept_dir: Option<PathBuf>,
+    kept: Mutex<Vec<KeptFile>>,
+    /// Ratings, tags and notes, by relative path of the original
+    meta: Mutex<BTreeMap<PathBuf, VideoMeta>>,
+    /// Playback positions, least recently played first
+    history: Mutex<Vec<HistoryEntry>>,
+    /// Set when the state has changed since it was last written out
+    state_dirty: AtomicBool,
+    /// Wakes `run_state_saves` when the state has changed
+    save_notify_tx: mpsc::Sender<()>,
+    save_notify_rx: Mutex<Option<mpsc::Receiver<()>>>,
+    bias: Bias,
+    /// Running total of the space taken up by our entries in the files dir, in bytes
+    cache_used: Mutex<u64>,
     delete_notify_tx: mpsc::Sender<()>,
     delete_notify_rx: Mutex<Option<mpsc::Receiver<()>>>,
+    /// Wakes `probe_durations` when there may be files to probe
+    probe_notify_tx: mpsc::Sender<()>,
+    probe_notify_rx: Mutex<Option<mpsc::Receiver<()>>>,
+    events_tx: broadcast::Sender<Event>,
     cancellation_token: CancellationToken,
 }
 
 impl Player {
-    pub fn new(dir_path: &Path, codec: Option<&str>, buffer_count: usize, no_delete: bool, always_reencode: bool, denoise: bool) -> Self {
-        let mut files: Vec<_> = get_media_files(dir_path)
-            .map(|entry| File {
-                id: Uuid::new_v4().to_string(),
-                original_path: entry.path().to_path_buf(),
-                path: None,
-            })
-            .collect();
-        files.sort_by_key(|f| f.id.clone());
+    pub fn new(dir_path: &Path, options: PlayerOptions) -> Result<Self, PlayerError> {
+        let PlayerOptions { codec, buffer_count, workers, no_delete, always_reencode, audio_filters, progressive, output, order, state_dir, cache_dir, trash_dir, trash_retention, undo_window, kept_dir, prefer_tags, avoid_tags, unseen_first, cache_max_bytes, direct_serve } = options;
+
+        let (tmp_dir, files_dir, state_path) = match state_dir {
+            Some(state_dir) => {
+                std::fs::create_dir_all(&state_dir)?;
+                check_outside_media_dir(&state_dir, "State dir", dir_path)?;
+                let files_dir = state_dir.join("files");
+                (None, files_dir, Some(state_dir.join("state.json")))
+            }
+            None => {
+                let tmp_dir = tempfile::Builder::new().prefix("browser-player").tempdir()?;
+                let files_dir = tmp_dir.path().to_path_buf();
+                (Some(tmp_dir), files_dir, None)
+            }
+        };
+        let keyed_outputs = cache_dir.is_some();
+        if let Some(ref cache_dir) = cache_dir {
+            std::fs::create_dir_all(cache_dir)?;
+            check_outside_media_dir(cache_dir, "Cache dir", dir_path)?;
+        }
+        let files_dir = cache_dir.map(|dir| dir.join(CACHE_SUBDIR)).unwrap_or(files_dir);
+        std::fs::create_dir_all(&files_dir)?;
+
+        let state = match state_path {
+            Some(ref path) => State::load(path)?,
+            None => State::default(),
+        };
+
+        // Canonicalised like the media dir, so kept files found by the watcher can be recognised
+        let kept_dir = match kept_dir {
+            Some(dir) => {
+                std::fs::create_dir_all(&dir)?;
+                Some(dir.canonicalize()?)
+            }
+            None => None,
+        };
+        let kept = state.kept;
+
+        let files = restore_queue(dir_path, &files_dir, state.files, |path| is_kept(path, dir_path, kept_dir.as_deref(), &kept));
 
-        log::info!("Found {} files", files.len());
+        let trash = match trash_dir {
+            Some(ref dir) => {
+                let trash = Trash::new(dir, trash_retention)?;
+                check_outside_media_dir(dir, "Trash dir", dir_path)?;
+                Some(trash)
+            }
+            None => None,
+        };
+
+        let tracks_dir = files_dir.join(TRACKS_DIR);
+        if tracks_dir.exists() {
+            std::fs::remove_dir_all(&tracks_dir)?;
+        }
+        std::fs::create_dir(&tracks_dir)?;
 
         let (tx, rx) = mpsc::channel(16);
+        let (probe_tx, probe_rx) = mpsc::channel(16);
+        let (save_tx, save_rx) = mpsc::channel(1);
 
-        Self {
+        let player = Self {
             media_dir: dir_path.to_path_buf(),
-            tmp_dir: tempfile::Builder::new().prefix("browser-player").tempdir().expect("Could not create temp dir"),
+            files_dir,
+            _tmp_dir: tmp_dir,
+            tracks_dir,
+            making_tracks: Mutex::new(HashSet::new()),
+            keyed_outputs,
+            state_path,
             files: Mutex::new(files),
-            codec: codec.map(|s| s.to_string()),
+            converting: Mutex::new(HashMap::new()),
+            codec,
             buffer_count,
+            cache_max_bytes,
+            workers: workers.max(1),
             no_delete,
             always_reencode,
-            denoise,
+            direct_serve,
+            audio_filters,
+            progressive,
+            output,
+            order: Mutex::new(order),
+            trash,
+            undo_window,
+            pending_deletes: Mutex::new(HashMap::new()),
+            kept_dir,
+            kept: Mutex::new(kept),
+            meta: Mutex::new(state.meta),
+            history: Mutex::new(state.history),
+            state_dirty: AtomicBool::new(false),
+            save_notify_tx: save_tx,
+            save_notify_rx: Mutex::new(Some(save_rx)),
+            bias: Bias { prefer_tags, avoid_tags, unseen_first },
+            cache_used: Mutex::new(0),
             delete_notify_tx: tx,
             delete_notify_rx: Mutex::new(Some(rx)),
+            probe_notify_tx: probe_tx,
+            probe_notify_rx: Mutex::new(Some(probe_rx)),
+            events_tx: broadcast::channel(64).0,
             cancellation_token: CancellationToken::new(),
+        };
+
+        if player.keyed_outputs {
+            player.reuse_cached_outputs();
+        }
+        player.remove_stale_outputs();
+        *player.cache_used.lock().unwrap() = player.scan_cache_usage();
+        {
+            let mut files = player.files.lock().unwrap();
+            // A stored random order is kept, so restarting doesn't reshuffle
+            if order != Order::Random {
+                arrange(&mut files, order, &HashMap::new());
+            }
+            player.apply_bias(&mut files, &HashMap::new());
+            log::info!("Found {} files ({} already converted)", files.len(), files.iter().filter(|f| f.is_converted()).count());
         }
+
+        // Written straight away, so a state dir that can't be written to is found out now
+        player.save_state();
+        player.flush_state()?;
+        Ok(player)
+    }
+
+    /// Identifies a conversion of a file: its original's path, modification time and size,
+    /// and the settings it's converted with. Returns `None` if the original can't be read.
+    fn cache_key(&self, file: &File) -> Option<String> {
+        let mut settings = format!("{:?}", (&self.codec, self.always_reencode, &self.audio_filters, self.progressive, self.output));
+        // Only added when set, so ordinary conversions keep the keys they had before these could be
+        if file.force_reencode || file.burn_subtitles.is_some() {
+            settings.push_str(&format!("\n{:?}", (file.force_reencode, file.burn_subtitles)));
+        }
+        cache_key(&file.original_path, self.relative_path(&file.original_path), &settings)
+    }
+
+    /// Picks up outputs left in the cache dir by an earlier run, for files that haven't changed since
+    fn reuse_cached_outputs(&self) {
+        let mut files = self.files.lock().unwrap();
+        for file in files.iter_mut() {
+            // Named afresh, in case the original has changed since
+            file.output_name = self.cache_key(file);
+            let output = self.output_path(file);
+            file.path = output.exists().then_some(output);
+            file.previews = self.previews_dir(file).exists();
+            file.subtitles = read_subtitles_manifest(&self.subtitles_dir(file));
+        }
+    }
+
+    /// Removes anything in the files dir that isn't a queued file's output:
+    /// orphaned outputs, interrupted conversions and, in a cache dir, outputs of originals that have changed
+    fn remove_stale_outputs(&self) {
+        let files = self.files.lock().unwrap();
+        let mut outputs: HashSet<_> = files.iter().filter_map(|f| f.path.as_ref()).map(|p| output_root(&self.files_dir, p)).collect();
+        outputs.extend(files.iter().filter(|f| !f.subtitles.is_empty()).map(|f| self.subtitles_dir(f)));
+        outputs.extend(files.iter().filter(|f| f.previews).map(|f| self.previews_dir(f)));
+        for entry in std::fs::read_dir(&self.files_dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path == self.tracks_dir {
+                continue;
+            }
+            if !outputs.contains(&path) {
+                log::info!("Removing stale output: {}", path.display());
+                let _ = remove_output(&path);
+            }
+        }
+    }
+
+    /// Adds up the space the entries in the files dir take up, in bytes
+    fn scan_cache_usage(&self) -> u64 {
+        std::fs::read_dir(&self.files_dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry_size(&entry.path()))
+            .sum()
+    }
+
+    /// Updates the running total of cache usage for an entry that's changed size from `before` to `after` bytes
+    fn update_cache_usage(&self, before: u64, after: u64) {
+        let mut used = self.cache_used.lock().unwrap();
+        *used = (*used + after).saturating_sub(before);
+    }
+
+    /// Removes an entry from the files dir, taking it off the cache usage
+    fn remove_entry(&self, path: &Path) -> std::io::Result<()> {
+        let size = entry_size(path);
+        remove_output(path)?;
+        self.update_cache_usage(size, 0);
+        Ok(())
     }
 
     pub fn files_dir(&self) -> PathBuf {
-        self.tmp_dir.path().to_path_buf()
+        self.files_dir.clone()
+    }
+
+    /// Path of the converted output for a file, which for HLS is its master playlist.
+    /// It's named by the file's cache key in a cache dir, and by its id otherwise.
+    pub fn output_path(&self, file: &File) -> PathBuf {
+        let name = self.output_name(file);
+        match self.output {
+            OutputFormat::Mp4 => self.files_dir.join(name).with_extension("mp4"),
+            OutputFormat::Hls => self.files_dir.join(name).join(HLS_MASTER_PLAYLIST),
+        }
+    }
+
+    /// What a file's outputs are named after: its cache key in a cache dir, and its id otherwise.
+    /// Once it's started converting, the key it had then.
+    fn output_name(&self, file: &File) -> String {
+        if !self.keyed_outputs {
+            return file.id.clone();
+        }
+        file.output_name.clone().or_else(|| self.cache_key(file)).unwrap_or_else(|| file.id.clone())
+    }
+
+    /// Directory a file's subtitles are converted into
+    fn subtitles_dir(&self, file: &File) -> PathBuf {
+        self.files_dir.join(format!("{}.subtitles", self.output_name(file)))
+    }
+
+    /// Directory a file's poster and seek previews are made in
+    fn previews_dir(&self, file: &File) -> PathBuf {
+        self.files_dir.join(format!("{}.previews", self.output_name(file)))
+    }
+
+    /// Returns the path of one of a file's previews: its poster, sprite sheet or thumbnail index.
+    /// Returns `None` if there's no such file, or it doesn't have previews.
+    pub fn preview_path(&self, id: &str, name: &str) -> Option<PathBuf> {
+        let file = self.file(id).filter(|file| file.previews)?;
+        [POSTER_FILE, SPRITE_FILE, THUMBNAILS_FILE]
+            .contains(&name)
+            .then(|| self.previews_dir(&file).join(name))
+    }
+
+    /// Makes what's served alongside a file once it's playable: its subtitles, and its poster and seek previews.
+    /// Anything that can't be made is logged and left out.
+    async fn make_extras(&self, file: &File, probe: &FfFormat) -> (Vec<Subtitle>, bool) {
+        let extras_size = || entry_size(&self.subtitles_dir(file)) + entry_size(&self.previews_dir(file));
+        let before = extras_size();
+        let subtitles = extract_subtitles(&file.original_path, probe, &self.subtitles_dir(file), &self.cancellation_token).await;
+        let previews = match generate_previews(&file.original_path, probe, &self.previews_dir(file), &self.cancellation_token).await {
+            Ok(()) => true,
+            Err(err) => {
+                log::error!("Couldn't make previews for {:?}: {}", file.original_path, err);
+                false
+            }
+        };
+        self.update_cache_usage(before, extras_size());
+        (subtitles, previews)
+    }
+
+    /// Makes a file's extras once it's been published as playable, then adds them to it and tells the page.
+    /// If it's left the queue in the meantime, they're removed again.
+    async fn add_extras(&self, file: &File, probe: &FfFormat) -> Result<(), PlayerError> {
+        let (subtitles, previews) = self.make_extras(file, probe).await;
+        let queued = match self.files.lock().unwrap().iter_mut().find(|f| f.id == file.id) {
+            Some(queued_file) => {
+                queued_file.subtitles = subtitles;
+                queued_file.previews = previews;
+                true
+            }
+            None => false,
+        };
+        if !queued {
+            let _ = self.remove_extras(file);
+            return Ok(());
+        }
+        self.save_state();
+        self.emit(Event::ExtrasReady { id: file.id.clone(), previews });
+        Ok(())
+    }
+
+    /// Removes what `make_extras` made for a file
+    fn remove_extras(&self, file: &File) -> std::io::Result<()> {
+        for dir in [self.subtitles_dir(file), self.previews_dir(file)] {
+            if dir.exists() {
+                self.remove_entry(&dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the path of one of a file's subtitles, by its position in the file's list.
+    /// Returns `None` if there's no such file or subtitle.
+    pub fn subtitle_path(&self, id: &str, index: usize) -> Option<PathBuf> {
+        let file = self.file(id)?;
+        let subtitle = file.subtitles.get(index)?;
+        Some(self.subtitles_dir(&file).join(&subtitle.file_name))
+    }
+
+    /// Returns a queued file by id
+    pub fn file(&self, id: &str) -> Option<File> {
+        self.files.lock().unwrap().iter().find(|f| f.id == id).cloned()
+    }
+
+    /// Removes a file's converted output
+    fn remove_converted(&self, path: &Path) -> std::io::Result<()> {
+        self.remove_entry(&output_root(&self.files_dir, path))
+    }
+
+    /// Removes everything made from a file: its converted output, subtitles, previews and any single audio track copies
+    fn remove_outputs(&self, file: &File) -> std::io::Result<()> {
+        if let Some(ref path) = file.path && path.exists() {
+            self.remove_converted(path)?;
+        }
+        self.remove_extras(file)?;
+        let prefix = format!("{}.", file.id);
+        for entry in std::fs::read_dir(&self.tracks_dir)?.flatten() {
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                self.remove_entry(&entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds a copy of a file with only one of its audio tracks (counting from 0), for browsers that can't
+    /// switch between tracks themselves. If it hasn't been made, the caller is told to make it with
+    /// `make_audio_track`, unless it's already being made.
+    /// Returns `None` if there's no such file or track, or the file isn't a playable MP4.
+    pub fn audio_track(&self, id: &str, track: usize) -> Option<AudioTrack> {
+        let file = self.file(id)?;
+        if file.info.as_ref().is_some_and(|info| track >= info.audio.len()) {
+            return None;
+        }
+        let source = match file.path {
+            _ if file.direct => file.original_path.clone(),
+            Some(path) if OutputFormat::of_output(&path) == OutputFormat::Mp4 => path,
+            _ => return None,
+        };
+
+        let output = self.tracks_dir.join(format!("{}.audio{}.mp4", file.id, track));
+        if output.exists() {
+            Some(AudioTrack::Ready(output))
+        } else if self.making_tracks.lock().unwrap().insert(output.clone()) {
+            Some(AudioTrack::Make { source, output })
+        } else {
+            Some(AudioTrack::Making)
+        }
+    }
+
+    /// Makes a copy of a video with only one of its audio tracks, as `audio_track` asked for
+    pub async fn make_audio_track(&self, source: &Path, output: &Path, track: usize) {
+        match extract_audio_track(source, output, track, &self.cancellation_token).await {
+            Ok(()) => self.update_cache_usage(0, entry_size(output)),
+            Err(err) => log::error!("Couldn't copy audio track {} of {}: {}", track, source.display(), err),
+        }
+        self.making_tracks.lock().unwrap().remove(output);
+    }
+
+    /// Path of an original, relative to the media dir
+    pub fn relative_path<'a>(&self, original_path: &'a Path) -> &'a Path {
+        original_path.strip_prefix(&self.media_dir).unwrap_or(original_path)
+    }
+
+    /// Converts a file in the configured output format, returning the output path and the probed input.
+    /// `burn_subtitles` is an image-based subtitle stream to draw onto the video.
+    /// Progress is recorded against the file's entry in `converting`, if it has one.
+    async fn convert(&self, file: &File, force_reencode: bool, burn_subtitles: Option<usize>) -> Result<(PathBuf, FfFormat), ConvertError> {
+        let output = self.output_path(file);
+        let input = file.original_path.to_str().unwrap();
+        let before = entry_size(&output_root(&self.files_dir, &output));
+
+        let on_progress = |progress: Progress| {
+            if let Some(conversion) = self.converting.lock().unwrap().get_mut(&file.id) {
+                conversion.progress = progress;
+            }
+        };
+
+        let probe = match self.output {
+            OutputFormat::Mp4 => {
+                let options = Mp4Options {
+                    codec: self.codec.as_deref(),
+                    force_reencode,
+                    audio_filters: &self.audio_filters,
+                    progressive: self.progressive,
+                    burn_subtitles,
+                    cancel: &self.cancellation_token,
+                };
+                convert_to_mp4(input, output.to_str().unwrap(), options, &on_progress).await?
+            }
+            OutputFormat::Hls => {
+                convert_to_hls(input, &output_root(&self.files_dir, &output), self.codec.as_deref(), &self.audio_filters, burn_subtitles, &self.cancellation_token, &on_progress).await?
+            }
+        };
+        self.update_cache_usage(before, entry_size(&output_root(&self.files_dir, &output)));
+        Ok((output, probe))
+    }
+
+    /// Marks a file as being converted
+    fn start_conversion(&self, converting: &mut HashMap<String, Conversion>, file: &File) {
+        converting.insert(
+            file.id.clone(),
+            Conversion {
+                id: file.id.clone(),
+                original_path: self.relative_path(&file.original_path).to_path_buf(),
+                progress: Progress::default(),
+            },
+        );
+    }
+
+    pub fn is_converting(&self, id: &str) -> bool {
+        self.converting.lock().unwrap().contains_key(id)
+    }
+
+    /// Returns the conversions currently running
+    pub fn conversions(&self) -> Vec<Conversion> {
+        let converting = self.converting.lock().unwrap();
+        converting.values().cloned().collect()
+    }
+
+    /// Whether a file can be played yet: it's converted, or in progressive MP4 mode its conversion
+    /// has written the index and first fragment
+    pub async fn is_ready(&self, file: &File) -> bool {
+        if file.is_converted() {
+            return true;
+        }
+        if !self.progressive || self.output != OutputFormat::Mp4 || !self.is_converting(&file.id) {
+            return false;
+        }
+        let tmp_output_path = in_progress_path(&self.output_path(file));
+        tokio::task::spawn_blocking(move || has_first_fragment(&tmp_output_path).unwrap_or(false))
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Has the queue written to the state file, if there is one, by `run_state_saves`
+    fn save_state(&self) {
+        self.state_dirty.store(true, Ordering::SeqCst);
+        let _ = self.save_notify_tx.try_send(());
+    }
+
+    /// Takes a copy of the state to write out, if it's changed since it was last written.
+    /// Copying it is quick, so the locks are only held for that and not for the write.
+    fn changed_state(&self) -> Option<State> {
+        self.state_path.as_ref()?;
+        if !self.state_dirty.swap(false, Ordering::SeqCst) {
+            return None;
+        }
+        let files = self.files.lock().unwrap();
+        Some(State {
+            files: files
+                .iter()
+                .map(|f| StoredFile {
+                    id: f.id.clone(),
+                    original_path: self.relative_path(&f.original_path).to_path_buf(),
+                    path: f.path.as_ref().and_then(|p| p.strip_prefix(&self.files_dir).ok()).map(Path::to_path_buf),
+                    direct: f.direct,
+                    subtitles: f.subtitles.clone(),
+                    previews: f.previews,
+                    info: f.info.clone(),
+                    error: f.error.clone().or_else(|| f.previous_error.clone()),
+                    force_reencode: f.force_reencode,
+                    burn_subtitles: f.burn_subtitles,
+                    output_name: f.output_name.clone(),
+                })
+                .collect(),
+            kept: self.kept.lock().unwrap().clone(),
+            meta: self.meta.lock().unwrap().clone(),
+            history: self.history.lock().unwrap().clone(),
+        })
+    }
+
+    /// Writes the state out now, if it's changed since it was last written
+    pub fn flush_state(&self) -> Result<(), PlayerError> {
+        let (Some(state), Some(state_path)) = (self.changed_state(), self.state_path.as_ref()) else {
+            return Ok(());
+        };
+        state.save(state_path).inspect_err(|_| self.state_dirty.store(true, Ordering::SeqCst))?;
+        Ok(())
+    }
+
+    /// Writes the state out whenever it changes, off the async runtime, until the player is cancelled.
+    /// A burst of changes, like many files added at once, goes out in one write. Playback progress
+    /// doesn't ask for a write, as it's reported every few seconds, so it goes out with the next change
+    /// or after a while.
+    pub async fn run_state_saves(&self) {
+        let mut rx = self.save_notify_rx.lock().unwrap().take()
+            .expect("run_state_saves can only be called once");
+        let mut interval = tokio::time::interval(PROGRESS_SAVE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = self.cancellation_token.cancelled() => return,
+                _ = interval.tick() => {}
+                _ = rx.recv() => tokio::time::sleep(STATE_SAVE_DELAY).await,
+            }
+            let (Some(state), Some(state_path)) = (self.changed_state(), self.state_path.clone()) else {
+                continue;
+            };
+            let result = match tokio::task::spawn_blocking(move || state.save(&state_path)).await {
+                Ok(result) => result.map_err(PlayerError::from),
+                Err(err) => Err(std::io::Error::other(err).into()),
+            };
+            if let Err(err) = result {
+                // Tried again with the next change
+                self.state_dirty.store(true, Ordering::SeqCst);
+                log::error!("Couldn't save state: {}", err);
+            }
+        }
+    }
+
+    /// Subscribes to changes in the queue
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events_tx.subscribe()
+    }
+
+    fn emit(&self, event: Event) {
+        // It's fine if nobody's listening
+        let _ = self.events_tx.send(event);
     }
 
     /// Cancels the conversion loop, allowing it to exit gracefully
@@ -94,16 +946,137 @@ impl Player {
         self.cancellation_token.cancel();
     }
 
+    /// Completes once the player has been cancelled
+    pub async fn cancelled(&self) {
+        self.cancellation_token.cancelled().await;
+    }
+
     /// Returns the number of converted files currently in the queue
     fn converted_count(&self) -> usize {
         let files = self.files.lock().unwrap();
-        files.iter().filter(|f| f.path.is_some()).count()
+        files.iter().filter(|f| f.is_converted()).count()
     }
 
-    /// Returns the next file that needs to be converted (has no path yet)
-    fn get_next_unconverted(&self) -> Option<File> {
-        let files = self.files.lock().unwrap();
-        files.iter().find(|f| f.path.is_none()).cloned()
+    /// Claims the next file that needs to be converted (has no path yet and isn't being converted),
+    /// so no other worker picks it up
+    fn claim_next_unconverted(&self) -> Option<File> {
+        let mut files = self.files.lock().unwrap();
+        let mut converting = self.converting.lock().unwrap();
+        let file = files.iter_mut().find(|f| !f.is_converted() && f.error.is_none() && !converting.contains_key(&f.id))?;
+        file.previous_error = None;
+        if self.keyed_outputs && file.output_name.is_none() {
+            file.output_name = self.cache_key(file);
+        }
+        self.start_conversion(&mut converting, file);
+        Some(file.clone())
+    }
+
+    /// Converts a claimed file and marks it as converted, or drops it from the queue if it can't be converted
+    async fn convert_file(&self, file: File) -> Result<(), PlayerError> {
+        if let Some(probe) = self.probe_direct(&file).await {
+            log::info!("Serving as is: {:?}", file.original_path);
+            self.converting.lock().unwrap().remove(&file.id);
+            let queued = match self.files.lock().unwrap().iter_mut().find(|f| f.id == file.id) {
+                Some(original_file) => {
+                    original_file.direct = true;
+                    original_file.info = Some(probe.info());
+                    true
+                }
+                // Removed from the queue while probing
+                None => false,
+            };
+            self.save_state();
+            if queued {
+                self.emit(Event::Converted { id: file.id.clone() });
+                self.add_extras(&file, &probe).await?;
+            }
+            return Ok(());
+        }
+
+        let result = self.convert(&file, self.always_reencode || file.force_reencode, file.burn_subtitles).await;
+        self.converting.lock().unwrap().remove(&file.id);
+
+        let (output, probe) = match result {
+            Err(ConvertError::Interrupted) => {
+                // Ctrl+C was pressed, propagate by returning an error
+                log::info!("Conversion interrupted by signal");
+                return Err(PlayerError::ConvertError(ConvertError::Interrupted));
+            }
+            Err(err) => {
+                log::error!("Ignoring file due to conversion error: {}", err);
+                self.fail(&file.id, &err);
+                self.save_state();
+                return Ok(());
+            }
+            Ok(result) => result,
+        };
+
+        let converted = {
+            let mut files = self.files.lock().unwrap();
+            if let Some(original_file) = files.iter_mut().find(|f| f.id == file.id) {
+                original_file.path = Some(output);
+                original_file.info = Some(probe.info());
+                true
+            } else {
+                // Removed from the queue while converting
+                let _ = self.remove_converted(&output);
+                false
+            }
+        };
+        self.save_state();
+
+        // It can be played now, while its subtitles and previews are made
+        if converted {
+            self.emit(Event::Converted { id: file.id.clone() });
+            self.add_extras(&file, &probe).await?;
+        }
+        Ok(())
+    }
+
+    /// Probes a file's original, returning the probe if it can be served as it is.
+    /// That's only done for MP4 output, and when the audio and video don't need changing.
+    async fn probe_direct(&self, file: &File) -> Option<FfFormat> {
+        let extension = file.original_path.extension()?.to_ascii_lowercase();
+        if !self.direct_serve
+            || self.output != OutputFormat::Mp4
+            || self.always_reencode
+            || file.force_reencode
+            || file.burn_subtitles.is_some()
+            || !self.audio_filters.is_empty()
+            || !DIRECT_EXTENSIONS.iter().any(|ext| extension == *ext)
+        {
+            return None;
+        }
+
+        let probe = probe_file(file.original_path.to_str()?).await.ok()?;
+        let original_path = file.original_path.clone();
+        let faststart = tokio::task::spawn_blocking(move || is_faststart(&original_path).unwrap_or(false))
+            .await
+            .unwrap_or(false);
+        (probe.is_browser_playable() && faststart).then_some(probe)
+    }
+
+    /// Marks a queued file as failed, so it's skipped for conversion and playback
+    fn fail(&self, id: &str, err: &ConvertError) {
+        if let Some(file) = self.files.lock().unwrap().iter_mut().find(|f| f.id == id) {
+            file.error = Some(err.to_string());
+        }
+        self.emit(Event::Failed { id: id.to_string(), error: err.to_string() });
+    }
+
+    /// Returns how much space converted files, and what's been made alongside them, take up in bytes.
+    /// Conversions in progress are counted once they finish.
+    pub fn cache_usage(&self) -> u64 {
+        *self.cache_used.lock().unwrap()
+    }
+
+    pub fn cache_max_bytes(&self) -> Option<u64> {
+        self.cache_max_bytes
+    }
+
+    /// Whether the cache has reached its limit, so no more conversions should be started
+    fn is_cache_full(&self) -> bool {
+        self.cache_max_bytes.is_some_and(|max| self.cache_usage() >= max)
     }
 
     pub async fn convert_all(&self) -> Result<(), PlayerError> {
@@ -111,55 +1084,44 @@ impl Player {
         let mut rx = self.delete_notify_rx.lock().unwrap().take()
             .expect("convert_all can only be called once");
 
-        loop {
-            // Convert files until we reach the buffer count
-            while self.converted_count() < self.buffer_count {
-                if let Some(mut file) = self.get_next_unconverted() {
-                    let output = self
-                        .tmp_dir
-                        .path()
-                        .join(file.id.clone())
-                        .with_extension("mp4");
-                    let input = file.original_path.to_str().unwrap();
-                    
-                    match convert_to_mp4(input, output.to_str().unwrap(), self.codec.as_deref(), self.always_reencode, self.denoise).await {
-                        Err(ConvertError::Interrupted) => {
-                            // Ctrl+C was pressed, propagate by returning an error
-                            log::info!("Conversion interrupted by signal");
-                            return Err(PlayerError::ConvertError(ConvertError::Interrupted));
-                        }
-                        Err(err) => {
-                            log::error!("Ignoring file due to conversion error: {}", err);
-                            self.delete(file.id.clone(), true).await?;
-                            continue;
-                        }
-                        Ok(()) => {}
-                    }
+        let mut jobs = FuturesUnordered::new();
 
-                    file.path = Some(output);
-                    let mut files = self.files.lock().unwrap();
-                    if let Some(original_file) = files.iter_mut().find(|f| f.id == file.id) {
-                        original_file.path = file.path;
-                    }
+        loop {
+            // Start conversions until we run out of workers, or the buffer would be full once they're done
+            // Running conversions are left to finish if the cache fills up, but no more are started
+            while jobs.len() < self.workers
+                && self.converted_count() + jobs.len() < self.buffer_count
+                && !self.is_cache_full()
+            {
+                if let Some(file) = self.claim_next_unconverted() {
+                    jobs.push(self.convert_file(file));
                 } else {
                     // No more files to convert
                     break;
                 }
             }
 
-            // Check if there are any files left at all
-            if self.files.lock().unwrap().is_empty() {
-                log::info!("All files processed, conversion complete");
-                return Ok(());
+            if jobs.is_empty() {
+                if self.files.lock().unwrap().is_empty() {
+                    log::info!("All files processed, waiting for new files...");
+                } else if self.is_cache_full() {
+                    log::info!("Cache full ({} bytes used), waiting for delete...", self.cache_usage());
+                } else {
+                    log::info!("Buffer full ({} converted), waiting for delete...", self.converted_count());
+                }
             }
 
-            // Wait for a delete notification before checking again
-            log::info!("Buffer full ({} converted), waiting for delete...", self.converted_count());
+            // Wait for a conversion to finish, or a delete (or new file) notification, before checking again
             tokio::select! {
                 _ = self.cancellation_token.cancelled() => {
+                    // Conversions still running have been told to stop, so wait for them to clean up after themselves
                     log::info!("Conversion cancelled");
+                    while jobs.next().await.is_some() {}
                     return Ok(());
                 }
+                Some(result) = jobs.next(), if !jobs.is_empty() => {
+                    result?;
+                }
                 result = rx.recv() => {
                     if result.is_none() {
                         // Channel closed, we're done
@@ -172,18 +1134,169 @@ impl Player {
 
     pub fn get_next_file(&self, after_id: Option<String>) -> Option<File> {
         let files = self.files.lock().unwrap();
+        let playable = || files.iter().filter(|f| f.error.is_none());
         if let Some(id) = after_id {
-            files
-                .iter()
+            playable()
                 .skip_while(|f| f.id != id)
                 .nth(1)
+                .or_else(|| playable().next())
                 .cloned()
-                .or_else(|| files.first().cloned())
         } else {
-            files.first().cloned()
+            playable().next().cloned()
         }
     }
 
+    /// Returns the ratings, tags and notes for a file
+    pub fn meta(&self, file: &File) -> VideoMeta {
+        let meta = self.meta.lock().unwrap();
+        meta.get(self.relative_path(&file.original_path)).cloned().unwrap_or_default()
+    }
+
+    /// Returns the ratings, tags and notes for a queued file, or `None` if there's no such file
+    pub fn video_meta(&self, id: &str) -> Option<VideoMeta> {
+        let file = self.files.lock().unwrap().iter().find(|f| f.id == id).cloned()?;
+        Some(self.meta(&file))
+    }
+
+    /// Replaces the ratings, tags and notes for a queued file.
+    /// Returns false if there's no such file.
+    pub fn set_meta(&self, id: &str, mut new_meta: VideoMeta) -> Result<bool, PlayerError> {
+        let Some(original_path) = self.files.lock().unwrap().iter().find(|f| f.id == id).map(|f| f.original_path.clone()) else {
+            return Ok(false);
+        };
+
+        let mut tags: Vec<String> = Vec::new();
+        for tag in new_meta.tags.drain(..) {
+            let tag = tag.trim();
+            if !tag.is_empty() && !tags.iter().any(|t| t == tag) {
+                tags.push(tag.to_string());
+            }
+        }
+        new_meta.tags = tags;
+        new_meta.note = new_meta.note.filter(|note| !note.trim().is_empty());
+
+        {
+            let mut meta = self.meta.lock().unwrap();
+            let key = self.relative_path(&original_path).to_path_buf();
+            if new_meta.is_empty() {
+                meta.remove(&key);
+            } else {
+                meta.insert(key, new_meta);
+            }
+        }
+        if !self.bias.is_empty() {
+            let mut files = self.files.lock().unwrap();
+            self.apply_bias(&mut files, &self.converting.lock().unwrap());
+        }
+        self.save_state();
+
+        // The next files to convert may have changed
+        let _ = self.delete_notify_tx.try_send(());
+        Ok(true)
+    }
+
+    /// Moves files with preferred tags ahead in the queue, and ones with avoided tags behind
+    fn apply_bias(&self, files: &mut [File], converting: &HashMap<String, Conversion>) {
+        let meta = self.meta.lock().unwrap();
+        let history = self.history.lock().unwrap();
+        self.bias.apply(
+            files,
+            |f| f.is_converted() || converting.contains_key(&f.id),
+            |f| meta.get(self.relative_path(&f.original_path)),
+            |f| is_seen(&history, self.relative_path(&f.original_path)),
+        );
+    }
+
+    /// Records how far a queued file has been played.
+    /// Returns false if there's no such file.
+    pub fn record_progress(&self, id: &str, position: f64) -> Result<bool, PlayerError> {
+        let Some(file) = self.files.lock().unwrap().iter().find(|f| f.id == id).cloned() else {
+            return Ok(false);
+        };
+        let original_path = self.relative_path(&file.original_path).to_path_buf();
+        let duration = file.info.and_then(|info| info.duration);
+
+        {
+            let mut history = self.history.lock().unwrap();
+            history.retain(|h| h.original_path != original_path);
+            history.push(HistoryEntry { original_path, position, duration, played_at: now() });
+            if history.len() > HISTORY_LIMIT {
+                let excess = history.len() - HISTORY_LIMIT;
+                history.drain(..excess);
+            }
+        }
+        // Written out by `run_state_saves` in a while, rather than on every report
+        self.state_dirty.store(true, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    /// Returns the position to resume a file from, if it was left part way through
+    pub fn resume_position(&self, file: &File) -> Option<f64> {
+        let history = self.history.lock().unwrap();
+        let original_path = self.relative_path(&file.original_path);
+        let entry = history.iter().rev().find(|h| h.original_path == original_path)?;
+        (PlayStatus::of(entry) != PlayStatus::Watched && entry.position > 0.0).then_some(entry.position)
+    }
+
+    /// Returns the play history, most recently played first
+    pub fn history(&self) -> Vec<HistoryEntry> {
+        self.history.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    /// Returns the id of the queued file with this original, relative to the media dir
+    pub fn find_id(&self, original_path: &Path) -> Option<String> {
+        let original_path = self.media_dir.join(original_path);
+        self.files.lock().unwrap().iter().find(|f| f.original_path == original_path).map(|f| f.id.clone())
+    }
+
+    /// Returns what ffprobe found out about a file, probing it now if it hasn't been already
+    pub async fn media_info(&self, id: &str) -> Result<Option<MediaInfo>, PlayerError> {
+        let original_path = {
+            let files = self.files.lock().unwrap();
+            let Some(file) = files.iter().find(|f| f.id == id) else {
+                return Ok(None);
+            };
+            if let Some(ref info) = file.info {
+                return Ok(Some(info.clone()));
+            }
+            file.original_path.clone()
+        };
+
+        let info = probe_file(original_path.to_str().unwrap()).await?.info();
+
+        if let Some(file) = self.files.lock().unwrap().iter_mut().find(|f| f.id == id) {
+            file.info = Some(info.clone());
+        }
+        self.save_state();
+
+        Ok(Some(info))
+    }
+
+    pub fn file_state(&self, file: &File) -> FileState {
+        if file.is_converted() {
+            FileState::Converted
+        } else if file.error.is_some() {
+            FileState::Failed
+        } else if self.is_converting(&file.id) {
+            FileState::Converting
+        } else {
+            FileState::Pending
+        }
+    }
+
+    /// Returns the queue in order, optionally only the files in one state, along with each file's state
+    pub fn list_files(&self, state: Option<FileState>) -> Vec<(File, FileState)> {
+        let files = self.files.lock().unwrap().clone();
+        files
+            .into_iter()
+            .map(|file| {
+                let file_state = self.file_state(&file);
+                (file, file_state)
+            })
+            .filter(|(_, file_state)| state.is_none_or(|state| state == *file_state))
+            .collect()
+    }
+
     fn get_file_base_dir(&self, file_path: &Path) -> Option<PathBuf> {
         let stripped = file_path.strip_prefix(&self.media_dir).unwrap();
         let mut parts = stripped.iter();
@@ -196,16 +1309,29 @@ impl Player {
         }
     }
 
-    fn delete_empty_file_dirs(&self, file_path: &Path) {
+    /// Cleans up the top-level folder a deleted file was in, once it has no videos left.
+    /// With a trash, whatever's left is moved into the file's trash entry.
+    async fn delete_empty_file_dirs(&self, id: &str, file_path: &Path) {
         if let Some(base_dir) = self.get_file_base_dir(file_path) {
             let remaining_files = get_media_files(&base_dir).count();
             if remaining_files == 0 {
-                log::warn!("Deleting empty file dir: {}", base_dir.display());
-                std::fs::remove_dir_all(base_dir).unwrap();
+                if let Some(ref trash) = self.trash {
+                    log::warn!("Moving empty file dir to trash: {}", base_dir.display());
+                    if let Err(err) = trash.put_dir(id, &base_dir, &self.media_dir).await {
+                        log::error!("Error moving {} to trash: {}", base_dir.display(), err);
+                    }
+                } else {
+                    log::warn!("Deleting empty file dir: {}", base_dir.display());
+                    if let Err(err) = tokio::fs::remove_dir_all(&base_dir).await {
+                        log::error!("Error deleting {}: {}", base_dir.display(), err);
+                    }
+                }
             }
         }
     }
 
+    /// Drops a file from the queue and deletes it once the undo window has passed,
+    /// or straight away if there isn't one
     pub async fn delete(&self, id: String, keep_original: bool) -> Result<(), PlayerError> {
         if self.no_delete {
             let files = self.files.lock().unwrap();
@@ -215,66 +1341,565 @@ impl Player {
             return Ok(());
         }
 
-        let original_path;
+        let (index, after, file) = {
+            let mut files = self.files.lock().unwrap();
+            let Some(index) = files.iter().position(|f| f.id == id) else {
+                return Ok(());
+            };
+            let after = index.checked_sub(1).map(|i| files[i].id.clone());
+            (index, after, files.remove(index))
+        };
+        log::info!("Delete: {:?}", file.original_path);
+        self.save_state();
+
+        if self.undo_window.is_zero() {
+            self.commit_delete(&file, keep_original).await?;
+        } else {
+            let deadline = Instant::now() + self.undo_window;
+            self.pending_deletes.lock().unwrap().insert(id.clone(), PendingDelete { file, after, index, keep_original, deadline });
+        }
+
+        // Notify the converter that a file was deleted
+        let _ = self.delete_notify_tx.send(()).await;
+        self.emit(Event::Deleted { id });
+        Ok(())
+    }
+
+    /// Removes a deleted file's converted output and, unless it's being kept, its original
+    async fn commit_delete(&self, file: &File, keep_original: bool) -> Result<(), PlayerError> {
+        self.remove_outputs(file)?;
+        if !keep_original {
+            // Subtitle files next to it go with it
+            let sidecars = find_sidecar_subtitles(&file.original_path).await;
+            match self.trash {
+                Some(ref trash) => {
+                    trash.put(&file.id, &file.original_path, self.relative_path(&file.original_path)).await?;
+                    for (path, _) in sidecars {
+                        if let Err(err) = trash.put_extra(&file.id, &path, self.relative_path(&path)).await {
+                            log::error!("Error moving {} to trash: {}", path.display(), err);
+                        }
+                    }
+                }
+                None => {
+                    tokio::fs::remove_file(&file.original_path).await?;
+                    for (path, _) in sidecars {
+                        if let Err(err) = tokio::fs::remove_file(&path).await {
+                            log::error!("Error deleting {}: {}", path.display(), err);
+                        }
+                    }
+                }
+            }
+            self.delete_empty_file_dirs(&file.id, &file.original_path).await;
+        }
+        Ok(())
+    }
+
+    /// Puts a file that's waiting to be deleted back where it was in the queue: after the file it came after,
+    /// which may have moved since. Unless the queue is shuffled, it's then put back in order.
+    /// Returns false if there's no such delete to undo.
+    pub fn undo_delete(&self, id: &str) -> Result<bool, PlayerError> {
+        let (pending, after) = {
+            let mut pending_deletes = self.pending_deletes.lock().unwrap();
+            let Some(pending) = pending_deletes.remove(id) else {
+                return Ok(false);
+            };
+            let after = undone_after(&pending_deletes, pending.after.clone());
+            (pending, after)
+        };
+        log::info!("Undo delete: {:?}", pending.file.original_path);
         {
             let mut files = self.files.lock().unwrap();
-            let file = files.iter_mut().find(|f| f.id == id);
-            if let Some(file) = file {
-                log::info!("Delete: {:?}", file.original_path);
-                if let Some(ref path) = file.path {
-                    std::fs::remove_file(path)?;
+            let order = *self.order.lock().unwrap();
+            let converting = self.converting.lock().unwrap();
+            reinsert(&mut files, pending.file, after.as_deref(), pending.index, order, &converting);
+            self.apply_bias(&mut files, &converting);
+        }
+        self.save_state();
+
+        let _ = self.delete_notify_tx.try_send(());
+        self.emit(Event::Restored { id: id.to_string() });
+        Ok(true)
+    }
+
+    /// Carries out deletes whose undo window has passed, or all of them if `all` is set
+    async fn commit_pending_deletes(&self, all: bool) {
+        let due: Vec<PendingDelete> = {
+            let mut pending = self.pending_deletes.lock().unwrap();
+            let now = Instant::now();
+            let (due, waiting) = pending.drain().partition(|(_, p)| all || p.deadline <= now);
+            *pending = waiting;
+            due.into_values().collect()
+        };
+        if due.is_empty() {
+            return;
+        }
+        for pending in due {
+            if let Err(err) = self.commit_delete(&pending.file, pending.keep_original).await {
+                log::error!("Error deleting {:?}: {}", pending.file.original_path, err);
+            }
+        }
+        // Space has been freed, which the converter may have been waiting for
+        let _ = self.delete_notify_tx.try_send(());
+    }
+
+    /// Carries out deletes as their undo windows pass, until the player is cancelled
+    pub async fn run_pending_deletes(&self) {
+        let mut interval = tokio::time::interval(PENDING_DELETE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = self.cancellation_token.cancelled() => return,
+                _ = interval.tick() => self.commit_pending_deletes(false).await,
+            }
+        }
+    }
+
+    /// Carries out all pending deletes straight away, e.g. when shutting down
+    pub async fn flush_pending_deletes(&self) {
+        self.commit_pending_deletes(true).await;
+    }
+
+    pub fn trash(&self) -> Option<&Trash> {
+        self.trash.as_ref()
+    }
+
+    /// Moves a deleted video back out of the trash and into the queue.
+    /// Returns false if it isn't in the trash.
+    pub async fn restore_from_trash(&self, id: &str) -> Result<bool, PlayerError> {
+        let Some(ref trash) = self.trash else {
+            return Ok(false);
+        };
+        match trash.restore(id, &self.media_dir).await? {
+            Some(path) => {
+                self.add_file(path)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Periodically purges expired videos from the trash, until the player is cancelled
+    pub async fn purge_trash(&self) {
+        let Some(ref trash) = self.trash else {
+            return;
+        };
+
+        let mut interval = tokio::time::interval(TRASH_PURGE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = self.cancellation_token.cancelled() => return,
+                _ = interval.tick() => {
+                    if let Err(err) = trash.purge_expired().await {
+                        log::error!("Error purging trash: {}", err);
+                    }
                 }
-                original_path = Some(file.original_path.clone());
-                if !keep_original {
-                    std::fs::remove_file(file.original_path.clone())?;
+            }
+        }
+    }
+
+    /// Whether ratings, tags, notes and playback history are saved, and so outlast a restart
+    pub fn has_state(&self) -> bool {
+        self.state_path.is_some()
+    }
+
+    /// Whether a keep would outlast a restart: either the original is moved out of the queue's way,
+    /// or the kept list is saved
+    pub fn can_keep(&self) -> bool {
+        self.kept_dir.is_some() || self.state_path.is_some()
+    }
+
+    /// Keeps a file: drops it from the queue without deleting it, so it isn't offered again.
+    /// With a kept dir, the original is moved there, into `collection` if one's given.
+    /// Returns false if there's no such file.
+    pub async fn keep(&self, id: &str, collection: Option<String>) -> Result<bool, PlayerError> {
+        let Some(file) = self.files.lock().unwrap().iter().find(|f| f.id == id).cloned() else {
+            return Ok(false);
+        };
+        let relative_path = self.relative_path(&file.original_path).to_path_buf();
+
+        if let Some(ref kept_dir) = self.kept_dir {
+            let mut kept_path = kept_dir.clone();
+            if let Some(ref collection) = collection {
+                kept_path.push(collection);
+            }
+            kept_path.push(&relative_path);
+            if kept_path.exists() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{} is already kept", kept_path.display()),
+                )
+                .into());
+            }
+            log::info!("Keep: {:?} -> {:?}", file.original_path, kept_path);
+            let sidecars = find_sidecar_subtitles(&file.original_path).await;
+            move_file(&file.original_path, &kept_path).await?;
+            // Subtitle files next to it go with it
+            for (path, _) in sidecars {
+                let kept_sidecar = kept_path.with_file_name(path.file_name().unwrap());
+                if kept_sidecar.exists() {
+                    log::error!("Not keeping {}: {} already exists", path.display(), kept_sidecar.display());
+                } else if let Err(err) = move_file(&path, &kept_sidecar).await {
+                    log::error!("Error keeping {}: {}", path.display(), err);
                 }
-            } else {
-                original_path = None;
             }
-            files.retain(|f| f.id != id);
+        } else {
+            log::info!("Keep: {:?}", file.original_path);
+        }
+
+        self.files.lock().unwrap().retain(|f| f.id != id);
+        self.remove_outputs(&file)?;
+        self.kept.lock().unwrap().push(KeptFile { original_path: relative_path, collection, kept_at: now() });
+        self.save_state();
+
+        let _ = self.delete_notify_tx.try_send(());
+        self.emit(Event::Kept { id: id.to_string() });
+        Ok(true)
+    }
+
+    /// Adds a newly found file to the end of the queue, unless it's already queued or has been kept
+    pub fn add_file(&self, original_path: PathBuf) -> Result<(), PlayerError> {
+        if is_kept(&original_path, &self.media_dir, self.kept_dir.as_deref(), &self.kept.lock().unwrap()) {
+            return Ok(());
+        }
+        let file = File::new(original_path);
+        let id = file.id.clone();
+        {
+            let mut files = self.files.lock().unwrap();
+            if files.iter().any(|f| f.original_path == file.original_path) {
+                return Ok(());
+            }
+            log::info!("Added: {:?}", file.original_path);
+            files.push(file);
+
+            let order = *self.order.lock().unwrap();
+            let converting = self.converting.lock().unwrap();
+            if order != Order::Random {
+                arrange(&mut files, order, &converting);
+            }
+            self.apply_bias(&mut files, &converting);
         }
-        
-        if let Some(path) = original_path {
-            if !keep_original {
-                self.delete_empty_file_dirs(&path);
+        self.save_state();
+
+        // Wake the converter in case it's idle, and find the new file's duration if it's needed to sort it
+        let _ = self.delete_notify_tx.try_send(());
+        if *self.order.lock().unwrap() == Order::Duration {
+            let _ = self.probe_notify_tx.try_send(());
+        }
+        self.emit(Event::Added { id });
+        Ok(())
+    }
+
+    /// Changes the order of the queue
+    pub fn set_order(&self, order: Order) -> Result<(), PlayerError> {
+        log::info!("Ordering by {:?}", order);
+        *self.order.lock().unwrap() = order;
+        {
+            let mut files = self.files.lock().unwrap();
+            let converting = self.converting.lock().unwrap();
+            arrange(&mut files, order, &converting);
+            self.apply_bias(&mut files, &converting);
+        }
+        self.save_state();
+
+        // The next files to convert may have changed, and may change again as durations are found
+        let _ = self.delete_notify_tx.try_send(());
+        if order == Order::Duration {
+            let _ = self.probe_notify_tx.try_send(());
+        }
+        Ok(())
+    }
+
+    /// While ordering by duration, probes queued files that haven't been yet so they can be sorted,
+    /// a few at a time, rearranging the queue after each batch. Conversions carry on meanwhile, with
+    /// files that haven't been probed yet sorted last. Runs until cancelled, waking when files are added
+    /// or the order changes.
+    pub async fn probe_durations(&self) {
+        let mut rx = self.probe_notify_rx.lock().unwrap().take()
+            .expect("probe_durations can only be called once");
+
+        loop {
+            let unprobed: Vec<(String, PathBuf)> = if *self.order.lock().unwrap() == Order::Duration {
+                let files = self.files.lock().unwrap();
+                files.iter().filter(|f| f.info.is_none()).map(|f| (f.id.clone(), f.original_path.clone())).collect()
+            } else {
+                Vec::new()
+            };
+
+            if !unprobed.is_empty() {
+                log::info!("Probing {} files for their duration", unprobed.len());
+            }
+            for batch in unprobed.chunks(PROBE_BATCH) {
+                let mut probes = futures_util::stream::iter(batch)
+                    .map(|(id, original_path)| async move { (id, original_path, probe_file(original_path.to_str().unwrap()).await) })
+                    .buffer_unordered(PROBE_CONCURRENCY);
+                loop {
+                    let probed = tokio::select! {
+                        _ = self.cancellation_token.cancelled() => return,
+                        probed = probes.next() => probed,
+                    };
+                    let Some((id, original_path, result)) = probed else {
+                        break;
+                    };
+                    match result {
+                        Ok(probe) => {
+                            if let Some(file) = self.files.lock().unwrap().iter_mut().find(|f| f.id == *id) {
+                                file.info = Some(probe.info());
+                            }
+                        }
+                        Err(err) => log::warn!("Couldn't probe {:?}: {}", original_path, err),
+                    }
+                }
+
+                let order = *self.order.lock().unwrap();
+                if order == Order::Duration {
+                    let mut files = self.files.lock().unwrap();
+                    let converting = self.converting.lock().unwrap();
+                    arrange(&mut files, order, &converting);
+                    self.apply_bias(&mut files, &converting);
+                }
+                        self.save_state();
+                // The next files to convert may have changed
+                let _ = self.delete_notify_tx.try_send(());
+            }
+
+            tokio::select! {
+                _ = self.cancellation_token.cancelled() => return,
+                result = rx.recv() => {
+                    if result.is_none() {
+                        return;
+                    }
+                }
             }
-            // Notify the converter that a file was deleted
-            let _ = self.delete_notify_tx.send(()).await;
+        }
+    }
+
+    /// Drops queued files whose original no longer exists, along with their converted output
+    pub fn remove_missing(&self) -> Result<(), PlayerError> {
+        let removed: Vec<File> = {
+            let mut files = self.files.lock().unwrap();
+            let (present, missing) = files.drain(..).partition(|f| f.original_path.exists());
+            *files = present;
+            missing
+        };
+        if removed.is_empty() {
+            return Ok(());
+        }
+
+        for file in &removed {
+            log::info!("Removed: {:?}", file.original_path);
+            let _ = self.remove_outputs(file);
+        }
+        self.save_state();
+
+        let _ = self.delete_notify_tx.try_send(());
+        for file in removed {
+            self.emit(Event::Removed { id: file.id });
         }
         Ok(())
     }
 
     pub fn remove_from_queue(&self, id: &str) -> Option<File> {
-        let mut files = self.files.lock().unwrap();
-        let index = files.iter().position(|f| f.id == id)?;
-        Some(files.remove(index))
+        let file = {
+            let mut files = self.files.lock().unwrap();
+            let index = files.iter().position(|f| f.id == id)?;
+            files.remove(index)
+        };
+        self.save_state();
+        Some(file)
     }
 
-    pub async fn reencode_file(&self, file: File) -> Result<(), PlayerError> {
+    /// Converts a file again, re-encoding the video even if it could be copied.
+    /// `burn_subtitles` is an image-based subtitle stream (counting from 0) to draw onto the video.
+    pub async fn reencode_file(&self, file: File, burn_subtitles: Option<usize>) -> Result<(), PlayerError> {
         log::info!("Re-encoding: {:?}", file.original_path);
 
         // Delete the existing converted file if it exists
-        if let Some(ref path) = file.path && path.exists() {
-            std::fs::remove_file(path)?;
-        }
+        self.remove_outputs(&file)?;
 
-        // Re-encode the video with forced video transcoding
-        let output = self
-            .tmp_dir
-            .path()
-            .join(file.id.clone())
-            .with_extension("mp4");
-        let input = file.original_path.to_str().unwrap();
-        
-        convert_to_mp4(input, output.to_str().unwrap(), self.codec.as_deref(), true, self.denoise).await?;
+        // Re-encode the video with forced video transcoding, which is remembered so it's named apart from
+        // an ordinary conversion and converted the same way if it has to be again
+        let mut file = file;
+        file.force_reencode = true;
+        file.burn_subtitles = burn_subtitles;
+        file.output_name = None;
+        if self.keyed_outputs {
+            file.output_name = self.cache_key(&file);
+        }
+        self.start_conversion(&mut self.converting.lock().unwrap(), &file);
+        let result = self.convert(&file, true, burn_subtitles).await;
+        self.converting.lock().unwrap().remove(&file.id);
 
-        // Add the file back to the end of the queue
+        // Add the file back to the end of the queue, even if it failed so it's not forgotten
         let mut new_file = file.clone();
-        new_file.path = Some(output);
-        
-        let mut files = self.files.lock().unwrap();
-        files.push(new_file);
+        new_file.path = None;
+        new_file.direct = false;
+        new_file.subtitles = Vec::new();
+        new_file.previews = false;
+        new_file.error = None;
+        new_file.previous_error = None;
+        let result = match result {
+            Ok((output, probe)) => {
+                new_file.path = Some(output);
+                new_file.info = Some(probe.info());
+                Ok(probe)
+            }
+            Err(err) => {
+                new_file.error = Some(err.to_string());
+                Err(err)
+            }
+        };
+        self.files.lock().unwrap().push(new_file);
+        self.save_state();
 
-        Ok(())
+        let probe = match result {
+            Ok(probe) => probe,
+            Err(err) => {
+                self.emit(Event::Failed { id: file.id, error: err.to_string() });
+                return Err(err.into());
+            }
+        };
+
+        self.emit(Event::Reencoded { id: file.id.clone() });
+        self.add_extras(&file, &probe).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(id: &str, original_path: &str, path: Option<&str>) -> StoredFile {
+        StoredFile {
+            id: id.to_string(),
+            original_path: PathBuf::from(original_path),
+            path: path.map(PathBuf::from),
+            direct: false,
+            subtitles: Vec::new(),
+            previews: false,
+            info: None,
+            error: None,
+            force_reencode: false,
+            burn_subtitles: None,
+            output_name: None,
+        }
+    }
+
+    /// A queued file whose id is its name, so where it ends up is easy to read
+    fn queued(name: &str) -> File {
+        let mut file = File::new(PathBuf::from(format!("/m/{}.mkv", name)));
+        file.id = name.to_string();
+        file
+    }
+
+    fn ids(files: &[File]) -> Vec<&str> {
+        files.iter().map(|f| f.id.as_str()).collect()
+    }
+
+    fn pending(name: &str, after: Option<&str>) -> (String, PendingDelete) {
+        let delete = PendingDelete {
+            file: queued(name),
+            after: after.map(str::to_string),
+            index: 0,
+            keep_original: false,
+            deadline: Instant::now(),
+        };
+        (name.to_string(), delete)
+    }
+
+    #[test]
+    fn undone_delete_goes_after_the_first_file_still_queued_before_it() {
+        let pending_deletes: HashMap<_, _> = [pending("c", Some("b")), pending("b", Some("a")), pending("z", None)].into();
+        assert_eq!(undone_after(&pending_deletes, Some("c".to_string())).as_deref(), Some("a"));
+        assert_eq!(undone_after(&pending_deletes, Some("d".to_string())).as_deref(), Some("d"));
+        assert_eq!(undone_after(&pending_deletes, Some("z".to_string())), None);
+        assert_eq!(undone_after(&pending_deletes, None), None);
+    }
+
+    #[test]
+    fn undone_delete_goes_back_after_the_file_it_followed() {
+        // Shuffled, so the queue is left as it is apart from the file put back
+        let mut files = vec![queued("c"), queued("a"), queued("d")];
+        reinsert(&mut files, queued("b"), Some("a"), 0, Order::Random, &HashMap::new());
+        assert_eq!(ids(&files), ["c", "a", "b", "d"]);
+
+        reinsert(&mut files, queued("e"), None, 3, Order::Random, &HashMap::new());
+        assert_eq!(ids(&files), ["e", "c", "a", "b", "d"]);
+    }
+
+    #[test]
+    fn undone_delete_falls_back_to_where_it_was_if_the_file_it_followed_has_gone() {
+        let mut files = vec![queued("a"), queued("c"), queued("d")];
+        reinsert(&mut files, queued("b"), Some("gone"), 1, Order::Random, &HashMap::new());
+        assert_eq!(ids(&files), ["a", "b", "c", "d"]);
+
+        reinsert(&mut files, queued("e"), Some("gone"), 10, Order::Random, &HashMap::new());
+        assert_eq!(ids(&files), ["a", "b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn undone_delete_is_put_back_in_order_unless_shuffled() {
+        let mut converted = queued("d");
+        converted.path = Some(PathBuf::from("/files/d.mp4"));
+        let mut files = vec![converted, queued("a"), queued("c")];
+        reinsert(&mut files, queued("b"), Some("c"), 0, Order::Name, &HashMap::new());
+        // Converted files stay at the front
+        assert_eq!(ids(&files), ["d", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn restore_queue_reconciles_stored_files_with_disk() {
+        let media_dir = tempfile::tempdir().unwrap();
+        let files_dir = tempfile::tempdir().unwrap();
+        for name in ["b.mkv", "a.mkv", "new.mkv", "kept.mkv", "notes.txt"] {
+            std::fs::write(media_dir.path().join(name), b"video").unwrap();
+        }
+        std::fs::write(files_dir.path().join("b.mp4"), b"converted").unwrap();
+
+        let mut failed = stored("b", "b.mkv", Some("b.mp4"));
+        failed.error = Some("exited with code 1".to_string());
+        let stored_files = vec![
+            failed,
+            stored("gone", "gone.mkv", None),
+            // Its output has gone, so it's converted again
+            stored("a", "a.mkv", Some("a.mp4")),
+        ];
+        let files = restore_queue(media_dir.path(), files_dir.path(), stored_files, |path| path.ends_with("kept.mkv"));
+
+        // Stored files keep their id and order, and new files go on the end
+        let ids: Vec<_> = files.iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(ids[..2], ["b", "a"]);
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[2].original_path, media_dir.path().join("new.mkv"));
+
+        assert_eq!(files[0].path, Some(files_dir.path().join("b.mp4")));
+        assert_eq!(files[1].path, None);
+
+        // Failed files are tried again, with the failure kept until they are
+        assert_eq!(files[0].error, None);
+        assert_eq!(files[0].previous_error.as_deref(), Some("exited with code 1"));
+    }
+
+    #[test]
+    fn cache_key_identifies_original_and_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let original_path = dir.path().join("film.mkv");
+        std::fs::write(&original_path, b"video").unwrap();
+        let relative_path = Path::new("film.mkv");
+
+        let key = cache_key(&original_path, relative_path, "settings").unwrap();
+        assert_eq!(key.len(), CACHE_KEY_LENGTH);
+        assert!(key.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_eq!(cache_key(&original_path, relative_path, "settings").unwrap(), key);
+
+        assert_ne!(cache_key(&original_path, relative_path, "other settings").unwrap(), key);
+        assert_ne!(cache_key(&original_path, Path::new("other/film.mkv"), "settings").unwrap(), key);
+
+        std::fs::write(&original_path, b"a different video").unwrap();
+        assert_ne!(cache_key(&original_path, relative_path, "settings").unwrap(), key);
+    }
+
+    #[test]
+    fn cache_key_needs_the_original() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(cache_key(&dir.path().join("missing.mkv"), Path::new("missing.mkv"), "settings"), None);
     }
 }
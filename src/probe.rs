@@ -0,0 +1,364 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::convert::ConvertError;
+
+#[derive(Deserialize, Debug)]
+pub struct FfStream {
+    pub index: u32,
+    pub codec_name: Option<String>,
+    pub codec_type: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub avg_frame_rate: Option<String>,
+    pub r_frame_rate: Option<String>,
+    pub bit_rate: Option<String>,
+    pub channels: Option<u32>,
+    pub channel_layout: Option<String>,
+    pub sample_rate: Option<String>,
+    pub pix_fmt: Option<String>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+/// Audio codecs that can be copied into an MP4 as they are
+const MP4_AUDIO_CODECS: [&str; 3] = ["aac", "mp3", "opus"];
+
+/// Subtitle codecs that are text, and so can be converted to WebVTT
+const TEXT_SUBTITLE_CODECS: [&str; 6] = ["subrip", "ass", "ssa", "mov_text", "webvtt", "text"];
+
+impl FfStream {
+    /// Whether this audio stream can be copied into an MP4 without re-encoding.
+    /// Opus beyond stereo needs a channel mapping that browsers don't handle well, so it's re-encoded.
+    pub fn is_mp4_audio(&self) -> bool {
+        match self.codec_name.as_deref() {
+            Some("opus") => self.channels.is_some_and(|channels| channels <= 2),
+            Some(codec) => MP4_AUDIO_CODECS.contains(&codec),
+            None => false,
+        }
+    }
+
+    /// Whether this is a text subtitle stream, rather than a bitmap one
+    pub fn is_text_subtitle(&self) -> bool {
+        self.codec_name.as_deref().is_some_and(|codec| TEXT_SUBTITLE_CODECS.contains(&codec))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct FfFormatInfo {
+    format_name: Option<String>,
+    duration: Option<String>,
+    bit_rate: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FfFormat {
+    streams: Vec<FfStream>,
+    format: Option<FfFormatInfo>,
+}
+
+impl FfFormat {
+    /// Duration of the input in seconds
+    pub fn duration(&self) -> Option<f64> {
+        self.format.as_ref()?.duration.as_ref()?.parse().ok()
+    }
+
+    pub fn video(&self) -> Option<&FfStream> {
+        self.streams.iter().find(|s| s.codec_type == "video")
+    }
+
+    pub fn audio_streams(&self) -> impl Iterator<Item = &FfStream> {
+        self.streams.iter().filter(|s| s.codec_type == "audio")
+    }
+
+    pub fn subtitle_streams(&self) -> impl Iterator<Item = &FfStream> {
+        self.streams.iter().filter(|s| s.codec_type == "subtitle")
+    }
+
+    /// Whether browsers can play the file as it is: H.264 (8-bit 4:2:0) video and AAC audio in an MP4
+    pub fn is_browser_playable(&self) -> bool {
+        let is_mp4 = self
+            .format
+            .as_ref()
+            .and_then(|f| f.format_name.as_deref())
+            .is_some_and(|name| name.split(',').any(|n| n == "mp4"));
+        let video_ok = self.video().is_some_and(|video| {
+            video.codec_name.as_deref() == Some("h264")
+                && video.pix_fmt.as_deref().is_none_or(|fmt| fmt == "yuv420p" || fmt == "yuvj420p")
+        });
+        let audio_ok = self
+            .audio_streams()
+            .all(|audio| audio.codec_name.as_deref() == Some("aac"));
+        is_mp4 && video_ok && audio_ok
+    }
+
+    /// Summarises the probe into what's worth keeping about a file
+    pub fn info(&self) -> MediaInfo {
+        let format = self.format.as_ref();
+        MediaInfo {
+            container: format.and_then(|f| f.format_name.clone()),
+            duration: self.duration(),
+            bit_rate: format.and_then(|f| f.bit_rate.as_ref()?.parse().ok()),
+            creation_time: format.and_then(|f| f.tags.get("creation_time").cloned()),
+            tags: format.map(|f| f.tags.clone()).unwrap_or_default(),
+            video: self.video().map(|video| VideoInfo {
+                codec: video.codec_name.clone(),
+                width: video.width,
+                height: video.height,
+                frame_rate: video
+                    .avg_frame_rate
+                    .as_deref()
+                    .and_then(parse_rate)
+                    .or_else(|| video.r_frame_rate.as_deref().and_then(parse_rate)),
+                bit_rate: video.bit_rate.as_ref().and_then(|b| b.parse().ok()),
+            }),
+            audio: self
+                .audio_streams()
+                .map(|audio| AudioInfo {
+                    index: audio.index,
+                    codec: audio.codec_name.clone(),
+                    channels: audio.channels,
+                    channel_layout: audio.channel_layout.clone(),
+                    sample_rate: audio.sample_rate.as_ref().and_then(|r| r.parse().ok()),
+                    bit_rate: audio.bit_rate.as_ref().and_then(|b| b.parse().ok()),
+                    language: audio.tags.get("language").cloned(),
+                    title: audio.tags.get("title").cloned(),
+                })
+                .collect(),
+            bitmap_subtitles: self
+                .subtitle_streams()
+                .enumerate()
+                .filter(|(_, subtitle)| !subtitle.is_text_subtitle())
+                .map(|(stream, subtitle)| BitmapSubtitleInfo {
+                    stream,
+                    codec: subtitle.codec_name.clone(),
+                    language: subtitle.tags.get("language").cloned(),
+                    title: subtitle.tags.get("title").cloned(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Parses an ffprobe rate such as `30000/1001`
+fn parse_rate(rate: &str) -> Option<f64> {
+    let (num, den) = rate.split_once('/')?;
+    let (num, den): (f64, f64) = (num.parse().ok()?, den.parse().ok()?);
+    if den == 0.0 || num == 0.0 {
+        None
+    } else {
+        Some(num / den)
+    }
+}
+
+/// What ffprobe found out about a file
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MediaInfo {
+    /// Container format(s), as named by ffprobe, e.g. `mov,mp4,m4a,3gp,3g2,mj2`
+    pub container: Option<String>,
+    /// Duration in seconds
+    pub duration: Option<f64>,
+    /// Overall bitrate in bits per second
+    pub bit_rate: Option<u64>,
+    pub creation_time: Option<String>,
+    /// Container tags, such as title and encoder
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    pub video: Option<VideoInfo>,
+    #[serde(default)]
+    pub audio: Vec<AudioInfo>,
+    /// Image-based subtitles, which can't be served as WebVTT but can be burned into the video
+    #[serde(default)]
+    pub bitmap_subtitles: Vec<BitmapSubtitleInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VideoInfo {
+    pub codec: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Frames per second
+    pub frame_rate: Option<f64>,
+    /// Bits per second
+    pub bit_rate: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AudioInfo {
+    /// Index of the stream within the original
+    pub index: u32,
+    pub codec: Option<String>,
+    pub channels: Option<u32>,
+    pub channel_layout: Option<String>,
+    /// Samples per second
+    pub sample_rate: Option<u32>,
+    /// Bits per second
+    pub bit_rate: Option<u64>,
+    pub language: Option<String>,
+    pub title: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BitmapSubtitleInfo {
+    /// Which subtitle stream this is, counting from 0, as passed to the reencode endpoint to burn it in
+    pub stream: usize,
+    pub codec: Option<String>,
+    pub language: Option<String>,
+    pub title: Option<String>,
+}
+
+/// Reads the type and size of the MP4 box at `offset`, or `None` past the last complete header.
+/// A size of 0 means the box runs to the end of the file.
+fn read_box_header(file: &mut std::fs::File, offset: u64) -> std::io::Result<Option<([u8; 4], u64)>> {
+    let mut header = [0; 8];
+    file.seek(SeekFrom::Start(offset))?;
+    if file.read_exact(&mut header).is_err() {
+        return Ok(None);
+    }
+    let kind = header[4..].try_into().unwrap();
+    let size = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+        // A 64-bit size follows the header
+        1 => {
+            let mut size = [0; 8];
+            if file.read_exact(&mut size).is_err() {
+                return Ok(None);
+            }
+            u64::from_be_bytes(size)
+        }
+        size => size as u64,
+    };
+    Ok(Some((kind, size)))
+}
+
+/// Whether an MP4's index (its `moov` box) comes before the media data,
+/// so a browser can start playing it without fetching the end of the file first
+pub fn is_faststart(path: &Path) -> std::io::Result<bool> {
+    let mut file = std::fs::File::open(path)?;
+    let mut offset = 0;
+    while let Some((kind, size)) = read_box_header(&mut file, offset)? {
+        match &kind {
+            b"moov" => return Ok(true),
+            b"mdat" => return Ok(false),
+            _ => {}
+        }
+        if size < 8 {
+            return Ok(false);
+        }
+        offset += size;
+    }
+    Ok(false)
+}
+
+/// Whether a fragmented MP4 that's still being written has its index and a whole first fragment,
+/// which is the least a browser needs to start playing it
+pub fn has_first_fragment(path: &Path) -> std::io::Result<bool> {
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let (mut offset, mut moov, mut moof) = (0, false, false);
+    while let Some((kind, size)) = read_box_header(&mut file, offset)? {
+        // A box that runs to the end, or past what's been written so far, isn't finished
+        if size < 8 || offset + size > len {
+            return Ok(false);
+        }
+        match &kind {
+            b"moov" => moov = true,
+            b"moof" => moof = true,
+            b"mdat" if moov && moof => return Ok(true),
+            _ => {}
+        }
+        offset += size;
+    }
+    Ok(false)
+}
+
+pub async fn probe_file(path: &str) -> Result<FfFormat, ConvertError> {
+    let args = vec!["-v", "quiet", "-print_format", "json", "-show_format", "-show_streams", path];
+
+    println!("{:?}", args.join(" "));
+
+    let proc = Command::new("ffprobe")
+        .args(args)
+        .kill_on_drop(true)
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+
+    let output = proc.wait_with_output().await?;
+
+    let format: FfFormat = serde_json::from_slice(&output.stdout)?;
+
+    Ok(format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An MP4 box with `size` bytes of content
+    fn mp4_box(kind: &[u8; 4], size: usize) -> Vec<u8> {
+        let mut data = ((size + 8) as u32).to_be_bytes().to_vec();
+        data.extend(kind);
+        data.extend(vec![0; size]);
+        data
+    }
+
+    fn write_boxes(boxes: &[Vec<u8>]) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), boxes.concat()).unwrap();
+        file
+    }
+
+    #[test]
+    fn faststart_when_moov_comes_before_mdat() {
+        let file = write_boxes(&[mp4_box(b"ftyp", 16), mp4_box(b"moov", 100), mp4_box(b"mdat", 1000)]);
+        assert!(is_faststart(file.path()).unwrap());
+    }
+
+    #[test]
+    fn not_faststart_when_mdat_comes_first() {
+        let file = write_boxes(&[mp4_box(b"ftyp", 16), mp4_box(b"mdat", 1000), mp4_box(b"moov", 100)]);
+        assert!(!is_faststart(file.path()).unwrap());
+    }
+
+    #[test]
+    fn faststart_follows_64_bit_sizes() {
+        let mut free = 1u32.to_be_bytes().to_vec();
+        free.extend(b"free");
+        free.extend(24u64.to_be_bytes());
+        free.extend([0; 8]);
+        let file = write_boxes(&[mp4_box(b"ftyp", 16), free, mp4_box(b"moov", 100)]);
+        assert!(is_faststart(file.path()).unwrap());
+    }
+
+    #[test]
+    fn not_faststart_without_moov() {
+        let file = write_boxes(&[mp4_box(b"ftyp", 16)]);
+        assert!(!is_faststart(file.path()).unwrap());
+        let file = write_boxes(&[b"not an mp4".to_vec()]);
+        assert!(!is_faststart(file.path()).unwrap());
+    }
+
+    #[test]
+    fn first_fragment_needs_whole_moof_and_mdat() {
+        let header = [mp4_box(b"ftyp", 16), mp4_box(b"moov", 100)];
+        assert!(!has_first_fragment(write_boxes(&header).path()).unwrap());
+
+        let mut boxes = header.to_vec();
+        boxes.push(mp4_box(b"moof", 50));
+        let mut mdat = mp4_box(b"mdat", 1000);
+        mdat.truncate(500);
+        boxes.push(mdat);
+        assert!(!has_first_fragment(write_boxes(&boxes).path()).unwrap());
+
+        boxes.pop();
+        boxes.push(mp4_box(b"mdat", 1000));
+        assert!(has_first_fragment(write_boxes(&boxes).path()).unwrap());
+    }
+}
@@ -1,25 +1,114 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
 use std::io::Write;
 use std::path::PathBuf;
-use std::sync::OnceLock;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::Mutex;
 
 use tempfile::NamedTempFile;
+use tokio::process::Command;
 
-/// The RNNoise model data, embedded at compile time
-const MODEL_DATA: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/rnnoise_model.rnnn"));
-
-/// Global holder for the model temp file (keeps it alive for the duration of the program)
-static MODEL_FILE: OnceLock<NamedTempFile> = OnceLock::new();
-
-/// Returns the path to a temporary file containing the RNNoise model.
-/// The file is created on first call and persists for the lifetime of the program.
-pub fn get_model_path() -> PathBuf {
-    MODEL_FILE
-        .get_or_init(|| {
-            let mut file = NamedTempFile::new().expect("Failed to create temp file for RNNoise model");
-            file.write_all(MODEL_DATA).expect("Failed to write RNNoise model to temp file");
-            file.flush().expect("Failed to flush RNNoise model file");
-            file
+/// Name of the model used if none is chosen, which is a good general-purpose one
+pub const DEFAULT_MODEL: &str = "somnolent-hogwash";
+
+/// RNNoise models embedded at compile time, by name
+const BUNDLED_MODELS: [(&str, &[u8]); 5] = [
+    ("somnolent-hogwash", include_bytes!(concat!(env!("OUT_DIR"), "/somnolent-hogwash.rnnn"))),
+    ("beguiling-drafter", include_bytes!(concat!(env!("OUT_DIR"), "/beguiling-drafter.rnnn"))),
+    ("conjoined-burgers", include_bytes!(concat!(env!("OUT_DIR"), "/conjoined-burgers.rnnn"))),
+    ("leavened-quisling", include_bytes!(concat!(env!("OUT_DIR"), "/leavened-quisling.rnnn"))),
+    ("marathon-prescription", include_bytes!(concat!(env!("OUT_DIR"), "/marathon-prescription.rnnn"))),
+];
+
+/// Temp files holding the bundled models that have been used (keeps them alive for the duration of the program)
+static MODEL_FILES: Mutex<BTreeMap<&str, NamedTempFile>> = Mutex::new(BTreeMap::new());
+
+#[derive(thiserror::Error, Debug)]
+pub enum ModelError {
+    #[error("Couldn't read denoise model {}: {}", .0.display(), .1)]
+    Unreadable(PathBuf, std::io::Error),
+
+    #[error("Denoise model {} doesn't load: {}", .0.display(), .1)]
+    Invalid(PathBuf, String),
+}
+
+/// An RNNoise model: one of the bundled ones, or a file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Model {
+    Bundled(&'static str),
+    File(PathBuf),
+}
+
+impl FromStr for Model {
+    type Err = Infallible;
+
+    /// Parses the name of a bundled model, or failing that a path to a model file
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match BUNDLED_MODELS.iter().find(|(name, _)| *name == s) {
+            Some((name, _)) => Model::Bundled(name),
+            None => Model::File(PathBuf::from(s)),
         })
-        .path()
-        .to_path_buf()
+    }
+}
+
+impl Default for Model {
+    fn default() -> Self {
+        Model::Bundled(DEFAULT_MODEL)
+    }
+}
+
+impl Model {
+    /// Returns a path ffmpeg can load the model from.
+    /// A bundled model is written to a temp file on first use, which persists for the lifetime of the program.
+    pub fn path(&self) -> PathBuf {
+        let name = match self {
+            Model::File(path) => return path.clone(),
+            Model::Bundled(name) => *name,
+        };
+        let mut files = MODEL_FILES.lock().unwrap();
+        files
+            .entry(name)
+            .or_insert_with(|| {
+                let (_, data) = BUNDLED_MODELS.iter().find(|(n, _)| *n == name).unwrap();
+                let mut file = NamedTempFile::new().expect("Failed to create temp file for RNNoise model");
+                file.write_all(data).expect("Failed to write RNNoise model to temp file");
+                file.flush().expect("Failed to flush RNNoise model file");
+                file
+            })
+            .path()
+            .to_path_buf()
+    }
+
+    /// Checks that ffmpeg can load the model, by denoising a moment of silence with it
+    pub async fn validate(&self) -> Result<(), ModelError> {
+        let path = self.path();
+        std::fs::metadata(&path).map_err(|err| ModelError::Unreadable(path.clone(), err))?;
+
+        let filter = format!("arnndn=m={}", path.to_str().unwrap());
+        #[rustfmt::skip]
+        let args = [
+            "-hide_banner", "-nostats",
+            "-f", "lavfi", "-i", "anullsrc=r=48000:cl=mono",
+            "-t", "0.1",
+            "-af", &filter,
+            "-f", "null", "-",
+        ];
+        let output = Command::new("ffmpeg")
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .await
+            .map_err(|err| ModelError::Invalid(path.clone(), format!("couldn't run ffmpeg: {}", err)))?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let reason = stderr.lines().rfind(|line| !line.trim().is_empty()).unwrap_or("ffmpeg failed");
+            Err(ModelError::Invalid(path, reason.to_string()))
+        }
+    }
 }
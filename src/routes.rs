@@ -1,11 +1,43 @@
-use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use std::path::{Component, Path};
+use std::time::Duration;
+
+use actix_files::NamedFile;
+use actix_web::http::header::{self, ByteRangeSpec, ContentRangeSpec};
+use actix_web::{delete, get, post, put, route, web, web::Bytes, HttpMessage, HttpRequest, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::convert::{in_progress_path, OutputFormat, POSTER_FILE, THUMBNAILS_FILE};
+use crate::order::Order;
+use crate::player::{AudioTrack, FileState, PlayStatus, Player, PlayerError};
+use crate::probe::MediaInfo;
+use crate::state::VideoMeta;
+
+/// Number of videos `/videos` returns if no limit is given
+const DEFAULT_PAGE_SIZE: usize = 100;
 
-use crate::player::{Player, PlayerError};
+/// How often to send a comment on an idle event stream, so proxies don't close it
+const EVENTS_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often to check for more output while streaming a video that's still being converted
+const STREAM_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 #[derive(Serialize)]
 struct Video {
     id: String,
+    /// Where to load the video from, relative to the page: an MP4, or an HLS master playlist
+    url: String,
+    format: OutputFormat,
+    /// Where to resume playing from, in seconds, if it was left part way through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<f64>,
+    /// Frame to show before it starts playing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    poster: Option<String>,
+    /// WebVTT index of seek preview thumbnails, each a region of a sprite sheet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnails: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -20,17 +52,274 @@ pub async fn get_random(
 ) -> Result<impl Responder, PlayerError> {
     let query = query.into_inner();
 
-    if let Some(file) = player.get_next_file(query.after_id) {
-        if file.path.is_some() {
-            Ok(HttpResponse::Ok().json(Video { id: file.id }))
-        } else {
-            Ok(HttpResponse::ServiceUnavailable().finish())
-        }
+    let Some(file) = player.get_next_file(query.after_id) else {
+        return Ok(HttpResponse::Gone().finish());
+    };
+
+    let (url, format) = if file.direct {
+        (format!("video/{}/original", file.id), OutputFormat::Mp4)
+    } else if let Some(ref path) = file.path {
+        let relative_path = path.strip_prefix(player.files_dir()).unwrap();
+        (format!("video-files/{}", relative_path.to_str().unwrap()), OutputFormat::of_output(path))
+    } else if player.is_ready(&file).await {
+        (format!("video/{}/stream", file.id), OutputFormat::Mp4)
+    } else {
+        return Ok(HttpResponse::ServiceUnavailable().finish());
+    };
+
+    let position = player.resume_position(&file);
+    let preview_url = |name: &str| file.previews.then(|| format!("video/{}/previews/{}", file.id, name));
+    let (poster, thumbnails) = (preview_url(POSTER_FILE), preview_url(THUMBNAILS_FILE));
+    Ok(HttpResponse::Ok().json(Video { id: file.id, url, format, position, poster, thumbnails }))
+}
+
+#[derive(Serialize)]
+struct VideoListing {
+    id: String,
+    /// Path of the original, relative to the media dir
+    path: String,
+    state: FileState,
+    /// Size of the original in bytes
+    size: Option<u64>,
+    /// Duration in seconds, once the file has been probed
+    duration: Option<f64>,
+    /// Why it couldn't be converted, including before a restart if it hasn't been tried again yet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten)]
+    meta: VideoMeta,
+}
+
+#[derive(Serialize)]
+struct VideoList {
+    /// Number of videos matching the filter, across all pages
+    total: usize,
+    videos: Vec<VideoListing>,
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    state: Option<FileState>,
+    /// Only list videos with this tag
+    tag: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// Lists the queue in playback order
+#[get("/videos")]
+pub async fn list_videos(
+    player: web::Data<Player>,
+    query: web::Query<ListQuery>,
+) -> Result<impl Responder, PlayerError> {
+    let query = query.into_inner();
+
+    let files: Vec<_> = player
+        .list_files(query.state)
+        .into_iter()
+        .map(|(file, state)| {
+            let meta = player.meta(&file);
+            (file, state, meta)
+        })
+        .filter(|(_, _, meta)| query.tag.as_ref().is_none_or(|tag| meta.has_tag(tag)))
+        .collect();
+    let total = files.len();
+    let videos = files
+        .into_iter()
+        .skip(query.offset.unwrap_or(0))
+        .take(query.limit.unwrap_or(DEFAULT_PAGE_SIZE))
+        .map(|(file, state, meta)| VideoListing {
+            path: player.relative_path(&file.original_path).to_string_lossy().into_owned(),
+            size: std::fs::metadata(&file.original_path).ok().map(|m| m.len()),
+            id: file.id,
+            state,
+            duration: file.info.and_then(|info| info.duration),
+            error: file.error.or(file.previous_error),
+            meta,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(VideoList { total, videos }))
+}
+
+#[derive(Serialize)]
+struct SubtitleListing {
+    /// Where to load the WebVTT from, relative to the page
+    url: String,
+    language: Option<String>,
+    title: Option<String>,
+    /// Whether it came from a file next to the original, rather than from within it
+    sidecar: bool,
+}
+
+#[derive(Serialize)]
+struct VideoDetails {
+    #[serde(flatten)]
+    info: MediaInfo,
+    /// Subtitles that have been converted to WebVTT
+    subtitles: Vec<SubtitleListing>,
+}
+
+/// What ffprobe found out about a video's original, and the subtitles that go with it
+#[get("/video/{id}/info")]
+pub async fn get_video_info(
+    player: web::Data<Player>,
+    id: web::Path<String>,
+) -> Result<impl Responder, PlayerError> {
+    let Some(info) = player.media_info(&id).await? else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let subtitles = player
+        .file(&id)
+        .map(|file| file.subtitles)
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+        .map(|(index, subtitle)| SubtitleListing {
+            url: format!("video/{}/subtitles/{}", id, index),
+            language: subtitle.language,
+            title: subtitle.title,
+            sidecar: subtitle.sidecar,
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(VideoDetails { info, subtitles }))
+}
+
+/// A video's poster frame, seek preview sprite sheet or the WebVTT index into it.
+/// The index refers to the sprite sheet relative to itself, so they're served from the same place.
+#[get("/video/{id}/previews/{name}")]
+pub async fn get_preview(
+    req: HttpRequest,
+    player: web::Data<Player>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, PlayerError> {
+    let (id, name) = path.into_inner();
+    match player.preview_path(&id, &name) {
+        Some(path) => Ok(NamedFile::open(path)?.into_response(&req)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+/// One of a video's subtitles, as WebVTT
+#[get("/video/{id}/subtitles/{index}")]
+pub async fn get_subtitles(
+    req: HttpRequest,
+    player: web::Data<Player>,
+    path: web::Path<(String, usize)>,
+) -> Result<HttpResponse, PlayerError> {
+    let (id, index) = path.into_inner();
+    match player.subtitle_path(&id, index) {
+        Some(path) => Ok(NamedFile::open(path)?.into_response(&req)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+/// A video's rating, tags and note
+#[get("/video/{id}/meta")]
+pub async fn get_video_meta(
+    player: web::Data<Player>,
+    id: web::Path<String>,
+) -> Result<impl Responder, PlayerError> {
+    match player.video_meta(&id) {
+        Some(meta) => Ok(HttpResponse::Ok().json(meta)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+/// Replaces a video's rating, tags and note.
+/// Refused without --state-dir, as there'd be nowhere to save them and they'd be lost on restart.
+#[put("/video/{id}/meta")]
+pub async fn set_video_meta(
+    player: web::Data<Player>,
+    id: web::Path<String>,
+    body: web::Json<VideoMeta>,
+) -> Result<impl Responder, PlayerError> {
+    if !player.has_state() {
+        return Ok(HttpResponse::BadRequest().body("Saving ratings, tags and notes needs --state-dir"));
+    }
+    let meta = body.into_inner();
+    if meta.rating.is_some_and(|rating| !(1..=5).contains(&rating)) {
+        return Ok(HttpResponse::BadRequest().body("Rating must be from 1 to 5"));
+    }
+
+    if player.set_meta(&id, meta)? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
+
+#[derive(Deserialize)]
+struct ProgressBody {
+    /// Playback position in seconds
+    position: f64,
+}
+
+/// Records how far a video has been played.
+/// Refused without --state-dir, as the history couldn't be saved and would be lost on restart.
+#[post("/video/{id}/progress")]
+pub async fn record_progress(
+    player: web::Data<Player>,
+    id: web::Path<String>,
+    body: web::Json<ProgressBody>,
+) -> Result<impl Responder, PlayerError> {
+    if !player.has_state() {
+        return Ok(HttpResponse::BadRequest().body("Recording playback history needs --state-dir"));
+    }
+    let position = body.into_inner().position;
+    if !position.is_finite() || position < 0.0 {
+        return Ok(HttpResponse::BadRequest().body("Invalid position"));
+    }
+
+    if player.record_progress(&id, position)? {
+        Ok(HttpResponse::NoContent().finish())
     } else {
-        Ok(HttpResponse::Gone().finish())
+        Ok(HttpResponse::NotFound().finish())
     }
 }
 
+#[derive(Serialize)]
+struct HistoryListing {
+    /// Id of the video, if it's still queued
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    /// Path of the original, relative to the media dir
+    path: String,
+    /// Playback position in seconds
+    position: f64,
+    duration: Option<f64>,
+    status: PlayStatus,
+    /// When it was last played, in seconds since the Unix epoch
+    played_at: u64,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    limit: Option<usize>,
+}
+
+/// Lists videos that have been played, most recently played first
+#[get("/history")]
+pub async fn get_history(
+    player: web::Data<Player>,
+    query: web::Query<HistoryQuery>,
+) -> Result<impl Responder, PlayerError> {
+    let history: Vec<_> = player
+        .history()
+        .into_iter()
+        .take(query.limit.unwrap_or(DEFAULT_PAGE_SIZE))
+        .map(|entry| HistoryListing {
+            id: player.find_id(&entry.original_path),
+            path: entry.original_path.to_string_lossy().into_owned(),
+            status: PlayStatus::of(&entry),
+            position: entry.position,
+            duration: entry.duration,
+            played_at: entry.played_at,
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(history))
+}
+
 #[derive(Deserialize)]
 struct DeleteQuery {
     keep: Option<bool>,
@@ -47,20 +336,80 @@ pub async fn delete_video(
     Ok(HttpResponse::NoContent().finish())
 }
 
+#[derive(Deserialize)]
+struct KeepQuery {
+    collection: Option<String>,
+}
+
+#[post("/video/{id}/keep")]
+pub async fn keep_video(
+    player: web::Data<Player>,
+    id: web::Path<String>,
+    query: web::Query<KeepQuery>,
+) -> Result<impl Responder, PlayerError> {
+    if !player.can_keep() {
+        return Ok(HttpResponse::BadRequest().body("Keeping needs --kept-dir or --state-dir"));
+    }
+    let collection = query.into_inner().collection;
+    // A collection is a single folder within the kept dir
+    if let Some(ref name) = collection {
+        let mut components = Path::new(name).components();
+        if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+            return Ok(HttpResponse::BadRequest().body("Invalid collection name"));
+        }
+    }
+
+    if player.keep(&id, collection).await? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
+
+#[post("/video/{id}/undo")]
+pub async fn undo_delete(
+    player: web::Data<Player>,
+    id: web::Path<String>,
+) -> Result<impl Responder, PlayerError> {
+    if player.undo_delete(&id)? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
+
+#[derive(Deserialize)]
+struct ReencodeQuery {
+    /// Image-based subtitle stream to burn into the video, counting from 0
+    burn_subtitles: Option<usize>,
+}
+
 #[post("/video/{id}/reencode")]
 pub async fn reencode_video(
     player: web::Data<Player>,
     id: web::Path<String>,
+    query: web::Query<ReencodeQuery>,
 ) -> Result<impl Responder, PlayerError> {
     let id_str = id.into_inner();
-    
+    let burn_subtitles = query.burn_subtitles;
+
+    // Only image-based subtitles can be burned in, so check there's such a stream before the file leaves the queue
+    if let Some(stream) = burn_subtitles {
+        let Some(info) = player.media_info(&id_str).await? else {
+            return Ok(HttpResponse::NotFound().finish());
+        };
+        if !info.bitmap_subtitles.iter().any(|subtitle| subtitle.stream == stream) {
+            return Ok(HttpResponse::BadRequest().body(format!("No image-based subtitle stream {} to burn in", stream)));
+        }
+    }
+
     // Remove from queue immediately (synchronous operation)
     if let Some(file) = player.remove_from_queue(&id_str) {
         let player_clone = player.clone();
         
         // Spawn the re-encoding task in the background after removal
         tokio::spawn(async move {
-            if let Err(err) = player_clone.reencode_file(file).await {
+            if let Err(err) = player_clone.reencode_file(file, burn_subtitles).await {
                 log::error!("Error re-encoding video {}: {}", id_str, err);
             }
         });
@@ -69,8 +418,258 @@ pub async fn reencode_video(
     Ok(HttpResponse::Accepted().finish())
 }
 
+/// Serves the original of a video that's already playable, in place of a converted copy
+#[get("/video/{id}/original")]
+pub async fn get_original(
+    req: HttpRequest,
+    player: web::Data<Player>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, PlayerError> {
+    match player.file(&id) {
+        Some(file) if file.direct => Ok(NamedFile::open(&file.original_path)?.into_response(&req)),
+        _ => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+/// Serves a video with only one of its audio tracks, for browsers that can't switch tracks in the same file.
+/// Making it means copying the whole video, so that's done in the background; until it's ready, the response
+/// is 202 Accepted and the request should be tried again.
+#[route("/video/{id}/audio/{track}", method = "GET", method = "HEAD")]
+pub async fn get_audio_track(
+    req: HttpRequest,
+    player: web::Data<Player>,
+    path: web::Path<(String, usize)>,
+) -> Result<HttpResponse, PlayerError> {
+    let (id, track) = path.into_inner();
+    match player.audio_track(&id, track) {
+        Some(AudioTrack::Ready(path)) => Ok(NamedFile::open(path)?.into_response(&req)),
+        Some(AudioTrack::Make { source, output }) => {
+            let player = player.clone();
+            actix_web::rt::spawn(async move { player.make_audio_track(&source, &output, track).await });
+            Ok(HttpResponse::Accepted().insert_header((header::RETRY_AFTER, "1")).finish())
+        }
+        Some(AudioTrack::Making) => Ok(HttpResponse::Accepted().insert_header((header::RETRY_AFTER, "1")).finish()),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+/// Streams a video that's still being converted, following the output as it grows.
+/// A range request is answered from what's been written so far, waiting for the conversion to reach its start.
+/// Once converted, it's served as a normal file.
+#[get("/video/{id}/stream")]
+pub async fn stream_video(
+    req: HttpRequest,
+    player: web::Data<Player>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, PlayerError> {
+    let id = id.into_inner();
+    let Some(file) = player.file(&id) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let output = player.output_path(&file);
+
+    // If the conversion finishes the file gets renamed, but the open handle still sees all of it
+    let mut file = match tokio::fs::File::open(in_progress_path(&output)).await {
+        Ok(file) => file,
+        Err(_) => {
+            return match NamedFile::open(&output) {
+                Ok(file) => Ok(file.into_response(&req)),
+                Err(_) => Ok(HttpResponse::NotFound().finish()),
+            };
+        }
+    };
+
+    // Only the first range is served, and the total length isn't known until the conversion is done
+    let range = match req.get_header::<header::Range>() {
+        Some(header::Range::Bytes(ranges)) => match ranges.first() {
+            Some(ByteRangeSpec::FromTo(start, end)) => Some((*start, Some(*end))),
+            Some(ByteRangeSpec::From(start)) => Some((*start, None)),
+            _ => return Ok(unsatisfiable_range(file.metadata().await?.len())),
+        },
+        _ => None,
+    };
+
+    let Some((start, end)) = range else {
+        let body = futures_util::stream::unfold((file, player, id), |(mut file, player, id)| async move {
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                // Check before reading, so nothing written before the conversion finished is missed
+                let converting = player.is_converting(&id);
+                match file.read(&mut buf).await {
+                    Ok(0) if !converting => return None,
+                    Ok(0) => tokio::time::sleep(STREAM_POLL_INTERVAL).await,
+                    Ok(n) => {
+                        buf.truncate(n);
+                        return Some((Ok(Bytes::from(buf)), (file, player, id)));
+                    }
+                    Err(err) => return Some((Err(err), (file, player, id))),
+                }
+            }
+        });
+        return Ok(HttpResponse::Ok()
+            .content_type("video/mp4")
+            .insert_header((header::ACCEPT_RANGES, "bytes"))
+            .streaming(body));
+    };
+
+    // Wait for the conversion to get as far as the start of the range
+    let written = loop {
+        let converting = player.is_converting(&id);
+        let written = file.metadata().await?.len();
+        if written > start {
+            break written;
+        }
+        if !converting {
+            return Ok(unsatisfiable_range(written));
+        }
+        tokio::time::sleep(STREAM_POLL_INTERVAL).await;
+    };
+    let end = end.unwrap_or(u64::MAX).min(written - 1);
+    if end < start {
+        return Ok(unsatisfiable_range(written));
+    }
+
+    let length = end - start + 1;
+    file.seek(std::io::SeekFrom::Start(start)).await?;
+    let body = futures_util::stream::unfold((file, length), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        let mut buf = vec![0; remaining.min(64 * 1024) as usize];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), (file, remaining - n as u64)))
+            }
+            Err(err) => Some((Err(err), (file, 0))),
+        }
+    });
+    Ok(HttpResponse::PartialContent()
+        .content_type("video/mp4")
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(header::ContentRange(ContentRangeSpec::Bytes { range: Some((start, end)), instance_length: None }))
+        .no_chunking(length)
+        .streaming(body))
+}
+
+/// Response to a range request that starts past the end of what's been written
+fn unsatisfiable_range(written: u64) -> HttpResponse {
+    HttpResponse::RangeNotSatisfiable()
+        .insert_header(header::ContentRange(ContentRangeSpec::Bytes { range: None, instance_length: Some(written) }))
+        .finish()
+}
+
+#[derive(Deserialize)]
+struct OrderRequest {
+    order: Order,
+}
+
+#[post("/queue/order")]
+pub async fn set_order(
+    player: web::Data<Player>,
+    request: web::Json<OrderRequest>,
+) -> Result<impl Responder, PlayerError> {
+    player.set_order(request.into_inner().order)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Serialize)]
+struct TrashListing {
+    id: String,
+    /// Where the video was, relative to the media dir
+    path: String,
+    /// When it was deleted, in seconds since the Unix epoch
+    trashed_at: u64,
+    /// When it'll be deleted for good, in seconds since the Unix epoch
+    expires_at: u64,
+}
+
+#[get("/trash")]
+pub async fn list_trash(player: web::Data<Player>) -> Result<impl Responder, PlayerError> {
+    let Some(trash) = player.trash() else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let entries: Vec<_> = trash
+        .list()
+        .into_iter()
+        .map(|entry| TrashListing {
+            expires_at: trash.expires_at(&entry),
+            id: entry.id,
+            path: entry.original_path.to_string_lossy().into_owned(),
+            trashed_at: entry.trashed_at,
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+#[post("/trash/{id}/restore")]
+pub async fn restore_from_trash(
+    player: web::Data<Player>,
+    id: web::Path<String>,
+) -> Result<impl Responder, PlayerError> {
+    if player.restore_from_trash(&id).await? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
+
+#[get("/conversions")]
+pub async fn get_conversions(player: web::Data<Player>) -> Result<impl Responder, PlayerError> {
+    Ok(HttpResponse::Ok().json(player.conversions()))
+}
+
+#[derive(Serialize)]
+struct CacheUsage {
+    /// Space taken up by converted videos and what's made alongside them, in bytes
+    used_bytes: u64,
+    max_bytes: Option<u64>,
+    /// Whether conversion is paused until space is freed
+    full: bool,
+}
+
+#[get("/cache")]
+pub async fn get_cache(player: web::Data<Player>) -> Result<impl Responder, PlayerError> {
+    let used_bytes = player.cache_usage();
+    let max_bytes = player.cache_max_bytes();
+    let full = max_bytes.is_some_and(|max| used_bytes >= max);
+    Ok(HttpResponse::Ok().json(CacheUsage { used_bytes, max_bytes, full }))
+}
+
+/// Server-sent events for changes to the queue
+#[get("/events")]
+pub async fn get_events(player: web::Data<Player>) -> Result<impl Responder, PlayerError> {
+    let events = player.subscribe();
+
+    let body = futures_util::stream::unfold(events, |mut events| async move {
+        loop {
+            let message = match tokio::time::timeout(EVENTS_KEEP_ALIVE_INTERVAL, events.recv()).await {
+                Ok(Ok(event)) => event.to_sse(),
+                // Missed some events, but there's nothing useful to tell the client
+                Ok(Err(RecvError::Lagged(_))) => continue,
+                Ok(Err(RecvError::Closed)) => return None,
+                Err(_) => ": keep-alive\n\n".to_string(),
+            };
+            return Some((Ok::<_, std::convert::Infallible>(Bytes::from(message)), events));
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body))
+}
+
 #[get("/")]
 pub async fn get_root() -> Result<impl Responder, PlayerError> {
     let page: &'static [u8] = include_bytes!("index.html");
     Ok(HttpResponse::Ok().content_type("text/html").body(page))
 }
+
+/// hls.js, bundled at build time so the page doesn't depend on a CDN
+#[get("/hls.min.js")]
+pub async fn get_hls_js() -> Result<impl Responder, PlayerError> {
+    let script: &'static str = include_str!(concat!(env!("OUT_DIR"), "/hls.min.js"));
+    Ok(HttpResponse::Ok().content_type("text/javascript").body(script))
+}
@@ -0,0 +1,137 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::convert::Subtitle;
+use crate::probe::MediaInfo;
+
+#[derive(thiserror::Error, Debug)]
+pub enum StateError {
+    #[error("IO Error: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("Couldn't (de)serialize state file: {0}")]
+    FormatError(#[from] serde_json::Error),
+}
+
+/// A queue entry as stored on disk.
+/// Paths are relative, so the media and state dirs can be moved (e.g. remounted in a container).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredFile {
+    pub id: String,
+    /// Path of the original, relative to the media dir
+    pub original_path: PathBuf,
+    /// File name of the converted output, relative to the files dir
+    pub path: Option<PathBuf>,
+    /// Whether the original is served as it is, instead of being converted
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub direct: bool,
+    /// Subtitles converted to WebVTT, in the video's subtitles dir
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subtitles: Vec<Subtitle>,
+    /// Whether a poster and seek previews have been made, in the video's previews dir
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub previews: bool,
+    /// What ffprobe found, once it's been probed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<MediaInfo>,
+    /// Why the file couldn't be converted. It's tried again when the player restarts,
+    /// and this is kept until then so the failure can still be looked up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Whether the video was asked to be re-encoded even though it could be copied
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub force_reencode: bool,
+    /// Image-based subtitle stream (counting from 0) drawn onto the video
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burn_subtitles: Option<usize>,
+    /// What its outputs in a cache dir are named after
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_name: Option<String>,
+}
+
+/// A file that was kept rather than deleted, so it isn't queued again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeptFile {
+    /// Where the original was, relative to the media dir
+    pub original_path: PathBuf,
+    /// The sub-collection of the kept dir it was filed into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    /// When it was kept, in seconds since the Unix epoch
+    pub kept_at: u64,
+}
+
+/// What's been noted about a video while triaging it
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct VideoMeta {
+    /// Star rating, from 1 to 5
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rating: Option<u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl VideoMeta {
+    pub fn is_empty(&self) -> bool {
+        self.rating.is_none() && self.tags.is_empty() && self.note.is_none()
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+}
+
+/// How far a video was played, the last time it was played
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    /// Where the original is, relative to the media dir
+    pub original_path: PathBuf,
+    /// Playback position in seconds
+    pub position: f64,
+    /// Duration in seconds, if it's known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    /// When it was last played, in seconds since the Unix epoch
+    pub played_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    /// The queue, in playback order
+    pub files: Vec<StoredFile>,
+    /// Files that have been kept, in the order they were kept
+    #[serde(default)]
+    pub kept: Vec<KeptFile>,
+    /// Ratings, tags and notes, by the original's path relative to the media dir.
+    /// Keyed on the path rather than the id, so they outlive the file leaving and rejoining the queue.
+    #[serde(default)]
+    pub meta: BTreeMap<PathBuf, VideoMeta>,
+    /// One entry per video played, least recently played first
+    #[serde(default)]
+    pub history: Vec<HistoryEntry>,
+}
+
+impl State {
+    /// Loads the state from `path`, returning an empty state if the file doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self, StateError> {
+        match std::fs::read(path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes the state to `path`, via a temp file so a crash never leaves a truncated state behind
+    pub fn save(&self, path: &Path) -> Result<(), StateError> {
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::state::StateError;
+
+/// A deleted video waiting in the trash
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrashEntry {
+    /// Id the video had in the queue
+    pub id: String,
+    /// Where the video was, relative to the media dir
+    pub original_path: PathBuf,
+    /// When it was deleted, in seconds since the Unix epoch
+    pub trashed_at: u64,
+}
+
+/// Deleted videos are moved here rather than unlinked, and only removed for good once they've
+/// been in the trash for longer than the retention period.
+///
+/// Each entry's files live in `files/<id>/`, under the same relative path they had in the media dir,
+/// along with anything else that was cleaned up alongside it.
+pub struct Trash {
+    dir: PathBuf,
+    retention: Duration,
+    entries: Mutex<Vec<TrashEntry>>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Moves a file, falling back to copying if it's going to another filesystem
+pub async fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    match tokio::fs::rename(from, to).await {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            tokio::fs::copy(from, to).await?;
+            tokio::fs::remove_file(from).await
+        }
+        result => result,
+    }
+}
+
+/// Lists the files under a directory, however deep
+async fn files_in(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let dir = dir.to_path_buf();
+    let files = tokio::task::spawn_blocking(move || {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .map(|e| e.into_path())
+            .collect()
+    });
+    files.await.map_err(std::io::Error::other)
+}
+
+impl Trash {
+    pub fn new(dir: &Path, retention: Duration) -> Result<Self, StateError> {
+        std::fs::create_dir_all(dir.join("files"))?;
+
+        let index_path = dir.join("trash.json");
+        let entries = match std::fs::read(&index_path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            retention,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    fn entry_dir(&self, id: &str) -> PathBuf {
+        self.dir.join("files").join(id)
+    }
+
+    fn save(&self, entries: &[TrashEntry]) -> Result<(), StateError> {
+        let index_path = self.dir.join("trash.json");
+        let tmp_path = index_path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(entries)?)?;
+        std::fs::rename(tmp_path, index_path)?;
+        Ok(())
+    }
+
+    /// Moves a video into the trash. `relative_path` is where it is within the media dir.
+    pub async fn put(&self, id: &str, path: &Path, relative_path: &Path) -> Result<(), StateError> {
+        move_file(path, &self.entry_dir(id).join(relative_path)).await?;
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|e| e.id != id);
+        entries.push(TrashEntry {
+            id: id.to_string(),
+            original_path: relative_path.to_path_buf(),
+            trashed_at: now(),
+        });
+        self.save(&entries)
+    }
+
+    /// Moves another file into an existing entry, like subtitles that go with its video.
+    /// `relative_path` is where it's restored to.
+    pub async fn put_extra(&self, id: &str, path: &Path, relative_path: &Path) -> Result<(), StateError> {
+        move_file(path, &self.entry_dir(id).join(relative_path)).await?;
+        Ok(())
+    }
+
+    /// Moves everything left in `dir` into an existing entry, then removes `dir`.
+    /// `media_dir` is what paths are kept relative to.
+    pub async fn put_dir(&self, id: &str, dir: &Path, media_dir: &Path) -> Result<(), StateError> {
+        let entry_dir = self.entry_dir(id);
+        for path in files_in(dir).await? {
+            let relative_path = path.strip_prefix(media_dir).unwrap();
+            move_file(&path, &entry_dir.join(relative_path)).await?;
+        }
+        tokio::fs::remove_dir_all(dir).await?;
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<TrashEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// When an entry will be purged, in seconds since the Unix epoch
+    pub fn expires_at(&self, entry: &TrashEntry) -> u64 {
+        entry.trashed_at + self.retention.as_secs()
+    }
+
+    /// Moves an entry's files back into `media_dir`, returning where the video is now,
+    /// or `None` if there's no such entry.
+    /// Nothing is moved if any of them would replace a file that's there now.
+    pub async fn restore(&self, id: &str, media_dir: &Path) -> Result<Option<PathBuf>, StateError> {
+        let Some(entry) = self.entries.lock().unwrap().iter().find(|e| e.id == id).cloned() else {
+            return Ok(None);
+        };
+
+        let entry_dir = self.entry_dir(id);
+        let moves: Vec<_> = files_in(&entry_dir)
+            .await?
+            .into_iter()
+            .map(|path| {
+                let restored_path = media_dir.join(path.strip_prefix(&entry_dir).unwrap());
+                (path, restored_path)
+            })
+            .collect();
+        for (_, restored_path) in &moves {
+            if tokio::fs::try_exists(restored_path).await? {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", restored_path.display()),
+                )
+                .into());
+            }
+        }
+        for (path, restored_path) in &moves {
+            move_file(path, restored_path).await?;
+        }
+        tokio::fs::remove_dir_all(&entry_dir).await?;
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|e| e.id != id);
+        self.save(&entries)?;
+
+        log::info!("Restored from trash: {:?}", entry.original_path);
+        Ok(Some(media_dir.join(entry.original_path)))
+    }
+
+    /// Permanently deletes entries that have been in the trash longer than the retention period.
+    /// An entry that can't be deleted stays in the trash, to be tried again next time.
+    pub async fn purge_expired(&self) -> Result<(), StateError> {
+        let now = now();
+        let expired: Vec<_> = self.list().into_iter().filter(|e| self.expires_at(e) <= now).collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        let mut result = Ok(());
+        let mut purged = Vec::new();
+        for entry in expired {
+            log::info!("Purging from trash: {:?}", entry.original_path);
+            match tokio::fs::remove_dir_all(self.entry_dir(&entry.id)).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => result = Err(err.into()),
+                _ => purged.push(entry.id),
+            }
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|e| !purged.contains(&e.id));
+        self.save(&entries)?;
+        result
+    }
+}
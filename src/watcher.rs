@@ -0,0 +1,102 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::player::{get_media_files, is_media_file, Player, PlayerError};
+
+/// How long a new file's size must stay unchanged before it's considered fully written
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+
+/// A new file that's waiting to finish being written
+struct Pending {
+    size: u64,
+    changed_at: Instant,
+}
+
+impl Pending {
+    fn new() -> Self {
+        Self {
+            size: 0,
+            changed_at: Instant::now(),
+        }
+    }
+}
+
+/// Watches the media dir, adding new videos to the queue and dropping ones that disappear.
+/// Runs until the player is cancelled.
+pub async fn watch(player: &Player, media_dir: &Path) -> Result<(), PlayerError> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })?;
+    watcher.watch(media_dir, RecursiveMode::Recursive)?;
+
+    log::info!("Watching for changes in: {}", media_dir.display());
+
+    let mut pending: HashMap<PathBuf, Pending> = HashMap::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            _ = player.cancelled() => {
+                return Ok(());
+            }
+            event = rx.recv() => {
+                let event = match event {
+                    Some(Ok(event)) => event,
+                    Some(Err(err)) => {
+                        log::error!("Error watching media dir: {}", err);
+                        continue;
+                    }
+                    None => return Ok(()),
+                };
+                if matches!(event.kind, EventKind::Access(_)) {
+                    continue;
+                }
+
+                let mut removed = false;
+                for path in event.paths {
+                    if path.is_dir() {
+                        // A whole directory may have been moved in
+                        for entry in get_media_files(&path) {
+                            pending.entry(entry.into_path()).or_insert_with(Pending::new);
+                        }
+                    } else if path.is_file() {
+                        if is_media_file(&path) {
+                            pending.entry(path).or_insert_with(Pending::new);
+                        }
+                    } else {
+                        removed = true;
+                    }
+                }
+                if removed {
+                    player.remove_missing()?;
+                }
+            }
+            _ = interval.tick() => {
+                let mut settled = Vec::new();
+                pending.retain(|path, file| {
+                    let Ok(metadata) = std::fs::metadata(path) else {
+                        return false;
+                    };
+                    if metadata.len() != file.size {
+                        file.size = metadata.len();
+                        file.changed_at = Instant::now();
+                    } else if file.changed_at.elapsed() >= SETTLE_TIME {
+                        settled.push(path.clone());
+                        return false;
+                    }
+                    true
+                });
+                for path in settled {
+                    player.add_file(path)?;
+                }
+            }
+        }
+    }
+}